@@ -0,0 +1,424 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Applying updates to an installation directory the way Firefox's updater does.
+
+use std::fs;
+use std::io::{self, ErrorKind, Read, Seek};
+use std::path::Path;
+
+use crate::extract::{create_parents, existing_path, safe_path};
+use crate::manifest::{parse_instructions, Instruction, ManifestError, UpdateType};
+use crate::patch::patch_bytes;
+use crate::{Mar, MarError, MarItem, Result};
+
+/// Name of the file in the installation directory listing what to remove before a complete
+/// update.
+const PRECOMPLETE: &str = "precomplete";
+
+/// Directory whose contents are never removed by the instructions in `precomplete`.
+const DISTRIBUTION_DIR: &str = "distribution/";
+
+/// Applies the update in a mar to an installation directory.
+///
+/// The instructions from the update manifest are performed in order. For complete updates the
+/// `remove` and `rmdir` instructions in the installation's `precomplete` file are performed
+/// first, except for anything in the `distribution/` directory which is left for the update to
+/// manage. Every path is checked before anything is changed, but unlike Firefox's updater a
+/// failed update is not rolled back.
+pub fn apply<R, P>(archive: &mut Mar<R>, install_dir: P) -> Result<()>
+where
+    R: Read + Seek,
+    P: AsRef<Path>,
+{
+    let install_dir = install_dir.as_ref();
+    let manifest = archive
+        .manifest()?
+        .ok_or_else(|| MarError::Malformed("MAR file has no update manifest".to_owned()))?;
+
+    let mut instructions = Vec::new();
+    if manifest.update_type == UpdateType::Complete {
+        instructions.extend(precomplete_instructions(install_dir)?);
+    }
+    instructions.extend(manifest.instructions);
+
+    // Check every instruction before changing anything.
+    for instruction in &instructions {
+        for path in paths(instruction) {
+            safe_path(install_dir, path)?;
+        }
+        if let Some(name) = entry(instruction) {
+            item(archive, name)?;
+        }
+    }
+
+    fs::create_dir_all(install_dir)?;
+
+    for instruction in &instructions {
+        match instruction {
+            Instruction::Add { path } => add(archive, install_dir, path)?,
+            Instruction::AddIf { test, path } => {
+                if exists(install_dir, test)? {
+                    add(archive, install_dir, path)?;
+                }
+            }
+            Instruction::AddIfNot { test, path } => {
+                if !exists(install_dir, test)? {
+                    add(archive, install_dir, path)?;
+                }
+            }
+            Instruction::Patch { patch, path } => {
+                apply_patch_entry(archive, patch, install_dir, path)?
+            }
+            Instruction::PatchIf { test, patch, path } => {
+                if exists(install_dir, test)? {
+                    apply_patch_entry(archive, patch, install_dir, path)?;
+                }
+            }
+            Instruction::Remove { path } => {
+                ignore_missing(fs::remove_file(existing_path(install_dir, path)?))?;
+            }
+            Instruction::RemoveDir { path } => {
+                // Directories that aren't empty are left alone.
+                match fs::remove_dir(existing_path(install_dir, path)?) {
+                    Err(e)
+                        if e.kind() != ErrorKind::NotFound
+                            && e.kind() != ErrorKind::DirectoryNotEmpty =>
+                    {
+                        return Err(e.into())
+                    }
+                    _ => {}
+                }
+            }
+            Instruction::RemoveDirRecursive { path } => {
+                ignore_missing(fs::remove_dir_all(existing_path(install_dir, path)?))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads the removal instructions from the installation's `precomplete` file.
+fn precomplete_instructions(install_dir: &Path) -> Result<Vec<Instruction>> {
+    let text = match fs::read_to_string(install_dir.join(PRECOMPLETE)) {
+        Ok(text) => text,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut instructions = Vec::new();
+    for instruction in parse_instructions(&text)? {
+        match instruction {
+            Instruction::Remove { ref path } | Instruction::RemoveDir { ref path } => {
+                if !path.starts_with(DISTRIBUTION_DIR) {
+                    instructions.push(instruction);
+                }
+            }
+            _ => {
+                return Err(MarError::Manifest(ManifestError {
+                    line: 0,
+                    message: format!("Unexpected instruction in {}: {}", PRECOMPLETE, instruction),
+                }))
+            }
+        }
+    }
+    Ok(instructions)
+}
+
+/// Returns the installation paths an instruction refers to.
+fn paths(instruction: &Instruction) -> Vec<&str> {
+    match instruction {
+        Instruction::Add { path }
+        | Instruction::Patch { path, .. }
+        | Instruction::Remove { path }
+        | Instruction::RemoveDir { path }
+        | Instruction::RemoveDirRecursive { path } => vec![path],
+        Instruction::AddIf { test, path }
+        | Instruction::AddIfNot { test, path }
+        | Instruction::PatchIf { test, path, .. } => vec![test, path],
+    }
+}
+
+/// Returns the archive entry an instruction reads from.
+fn entry(instruction: &Instruction) -> Option<&str> {
+    match instruction {
+        Instruction::Add { path }
+        | Instruction::AddIf { path, .. }
+        | Instruction::AddIfNot { path, .. } => Some(path),
+        Instruction::Patch { patch, .. } | Instruction::PatchIf { patch, .. } => Some(patch),
+        _ => None,
+    }
+}
+
+/// Looks up an entry the manifest refers to, using the same lookup as `Mar::entry`.
+fn item<R>(archive: &Mar<R>, name: &str) -> Result<MarItem> {
+    archive.entry(name).cloned().ok_or_else(|| {
+        MarError::Malformed(format!(
+            "Manifest refers to {:?} which is not in the archive",
+            name
+        ))
+    })
+}
+
+/// Adds or replaces a file from the entry of the same name, giving it the entry's mode.
+///
+/// As in Firefox's updater the file is written beside the target and then renamed over it, so an
+/// existing file is replaced rather than rewritten in place.
+fn add<R>(archive: &mut Mar<R>, install_dir: &Path, path: &str) -> Result<()>
+where
+    R: Read + Seek,
+{
+    let item = item(archive, path)?;
+    let target = create_parents(install_dir, path)?;
+    let dir = target.parent().unwrap_or(install_dir);
+    let mut file = tempfile::Builder::new()
+        .prefix(".mar-update")
+        .tempfile_in(dir)?;
+    io::copy(&mut archive.read(&item)?, file.as_file_mut())?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        // The setuid, setgid and sticky bits are never set.
+        let permissions = fs::Permissions::from_mode(item.flags & 0o777);
+        file.as_file().set_permissions(permissions)?;
+    }
+    file.persist(&target).map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// Patches an existing file, leaving it untouched if the patch cannot be applied.
+fn apply_patch_entry<R>(
+    archive: &mut Mar<R>,
+    patch_name: &str,
+    install_dir: &Path,
+    path: &str,
+) -> Result<()>
+where
+    R: Read + Seek,
+{
+    let item = item(archive, patch_name)?;
+    let target = create_parents(install_dir, path)?;
+    let source = fs::read(&target)?;
+    let mut patch = Vec::new();
+    archive.read(&item)?.read_to_end(&mut patch)?;
+
+    let patched = patch_bytes(&source, &patch).map_err(|e| match e {
+        MarError::InvalidPatch(reason) => {
+            MarError::InvalidPatch(format!("Unable to patch {:?}: {}", path, reason))
+        }
+        e => e,
+    })?;
+    // Patched files keep their existing mode.
+    Ok(fs::write(&target, patched)?)
+}
+
+fn exists(install_dir: &Path, path: &str) -> Result<bool> {
+    Ok(existing_path(install_dir, path)?.exists())
+}
+
+fn ignore_missing(result: io::Result<()>) -> Result<()> {
+    match result {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::patch::create_patch;
+    use crate::testing::{self, build};
+
+    /// Builds an update from the text of its manifest and its entries.
+    fn update(manifest: &str, entries: &[(&str, u32, &[u8])]) -> Mar<Cursor<Vec<u8>>> {
+        let mut builder = testing::builder();
+        builder.add_entry("updatev3.manifest", 0o644, manifest.as_bytes());
+        for &(name, flags, data) in entries {
+            builder.add_entry(name, flags, data);
+        }
+        Mar::from_buffer(Cursor::new(build(builder))).unwrap()
+    }
+
+    fn write(dir: &Path, name: &str, data: &str) {
+        let path = dir.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, data).unwrap();
+    }
+
+    fn read(dir: &Path, name: &str) -> Option<String> {
+        fs::read_to_string(dir.join(name)).ok()
+    }
+
+    #[test]
+    fn precomplete_runs_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        write(dir, "a.txt", "old");
+        write(dir, "stale.txt", "stale");
+        write(dir, "stale/b.txt", "stale");
+        write(dir, "distribution/extensions/x.xpi", "kept");
+        write(
+            dir,
+            PRECOMPLETE,
+            "remove \"a.txt\"\n\
+             remove \"stale.txt\"\n\
+             remove \"stale/b.txt\"\n\
+             remove \"distribution/extensions/x.xpi\"\n\
+             rmdir \"stale/\"\n\
+             rmdir \"distribution/extensions/\"\n\
+             rmdir \"distribution/\"\n",
+        );
+
+        let mut archive = update(
+            "type \"complete\"\nadd \"a.txt\"\n",
+            &[("a.txt", 0o644, b"new")],
+        );
+        apply(&mut archive, dir).unwrap();
+
+        assert_eq!(read(dir, "a.txt").as_deref(), Some("new"));
+        assert!(!dir.join("stale.txt").exists());
+        assert!(!dir.join("stale").exists());
+        assert_eq!(
+            read(dir, "distribution/extensions/x.xpi").as_deref(),
+            Some("kept")
+        );
+    }
+
+    #[test]
+    fn conditional_adds() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        write(dir, "present/old.xpi", "old");
+        write(dir, "prefs.js", "local");
+
+        let mut archive = update(
+            "type \"partial\"\n\
+             add-if \"present/\" \"present/a.xpi\"\n\
+             add-if \"missing/\" \"missing/b.xpi\"\n\
+             add-if-not \"prefs.js\" \"prefs.js\"\n\
+             add-if-not \"settings.ini\" \"settings.ini\"\n",
+            &[
+                ("present/a.xpi", 0o644, b"a"),
+                ("missing/b.xpi", 0o644, b"b"),
+                ("prefs.js", 0o644, b"default"),
+                ("settings.ini", 0o644, b"settings"),
+            ],
+        );
+        apply(&mut archive, dir).unwrap();
+
+        assert_eq!(read(dir, "present/a.xpi").as_deref(), Some("a"));
+        assert!(!dir.join("missing").exists());
+        assert_eq!(read(dir, "prefs.js").as_deref(), Some("local"));
+        assert_eq!(read(dir, "settings.ini").as_deref(), Some("settings"));
+    }
+
+    #[test]
+    fn duplicate_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut archive = update(
+            "type \"partial\"\nadd \"a.txt\"\n",
+            &[("a.txt", 0o644, b"first"), ("a.txt", 0o644, b"second")],
+        );
+        apply(&mut archive, dir.path()).unwrap();
+
+        // The same entry as `Mar::entry` is used.
+        assert_eq!(read(dir.path(), "a.txt").as_deref(), Some("first"));
+    }
+
+    #[test]
+    fn remove_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        write(dir, "full/a.txt", "a");
+        fs::create_dir(dir.join("empty")).unwrap();
+        write(dir, "tree/b/c.txt", "c");
+
+        let mut archive = update(
+            "type \"partial\"\n\
+             rmdir \"full/\"\n\
+             rmdir \"empty/\"\n\
+             rmdir \"missing/\"\n\
+             rmrfdir \"tree/\"\n\
+             rmrfdir \"missing/\"\n",
+            &[],
+        );
+        apply(&mut archive, dir).unwrap();
+
+        assert_eq!(read(dir, "full/a.txt").as_deref(), Some("a"));
+        assert!(!dir.join("empty").exists());
+        assert!(!dir.join("tree").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn add_replaces_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        write(dir, "tool", "old");
+        fs::set_permissions(dir.join("tool"), fs::Permissions::from_mode(0o644)).unwrap();
+
+        let mut archive = update(
+            "type \"partial\"\nadd \"tool\"\n",
+            &[("tool", 0o4755, b"new")],
+        );
+        apply(&mut archive, dir).unwrap();
+
+        assert_eq!(read(dir, "tool").as_deref(), Some("new"));
+        let mode = fs::metadata(dir.join("tool")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o755);
+    }
+
+    #[test]
+    fn patch_with_wrong_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        write(dir, "a.txt", "something else");
+
+        let patch = create_patch(b"original contents", b"patched contents").unwrap();
+        let mut archive = update(
+            "type \"partial\"\npatch \"a.txt.patch\" \"a.txt\"\n",
+            &[("a.txt.patch", 0o644, &patch)],
+        );
+        let result = apply(&mut archive, dir);
+
+        assert!(matches!(result, Err(MarError::InvalidPatch(_))));
+        assert_eq!(read(dir, "a.txt").as_deref(), Some("something else"));
+
+        write(dir, "a.txt", "original contents");
+        apply(&mut archive, dir).unwrap();
+        assert_eq!(read(dir, "a.txt").as_deref(), Some("patched contents"));
+    }
+
+    #[test]
+    fn unsafe_paths_change_nothing() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("install");
+        write(root.path(), "outside.txt", "outside");
+        write(&dir, "b.txt", "b");
+        let absolute = root.path().join("outside.txt");
+
+        for path in ["../outside.txt", absolute.to_str().unwrap()] {
+            let manifest = format!(
+                "type \"partial\"\nadd \"a.txt\"\nremove \"b.txt\"\nremove \"{}\"\n",
+                path
+            );
+            let mut archive = update(&manifest, &[("a.txt", 0o644, b"a")]);
+            let result = apply(&mut archive, &dir);
+
+            assert!(
+                matches!(result, Err(MarError::UnsafeName { .. })),
+                "{:?}",
+                path
+            );
+            assert!(!dir.join("a.txt").exists());
+            assert_eq!(read(&dir, "b.txt").as_deref(), Some("b"));
+            assert_eq!(read(root.path(), "outside.txt").as_deref(), Some("outside"));
+        }
+    }
+}
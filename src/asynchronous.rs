@@ -0,0 +1,467 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Reading MAR files with tokio.
+//!
+//! This mirrors the blocking interface of `Mar` for readers implementing tokio's `AsyncRead` and
+//! `AsyncSeek`. Entries are decompressed as they are read without blocking the runtime.
+
+use std::io::{self, Cursor, ErrorKind, SeekFrom};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::{
+    AsyncBufRead, AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, BufReader, ReadBuf, Take,
+};
+
+use crate::compression::{decompression_error, header_len, BZ2_HEADER, XZ_HEADER};
+use crate::error::truncated;
+use crate::read::{
+    check_index_bounds, check_span, info_from_offsets, parse_header, parse_index, ReadOptions,
+    MAR_ID_SIZE, MAX_INFO_SIZE,
+};
+use crate::{Index, MarFileInfo, MarItem, Result};
+
+/// Read metadata from a MAR file.
+pub async fn get_info<R>(archive: &mut R) -> Result<MarFileInfo>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    let offset_to_index = read_header(archive).await?;
+
+    // The content starts at the first entry's data, or at the index if there are no entries.
+    let size_of_index = seek_index(archive, offset_to_index).await?;
+    let offset_to_content = if size_of_index >= 4 {
+        archive.read_u32().await.map_err(truncated)?
+    } else {
+        offset_to_index
+    };
+
+    // The rest of the metadata comes from the signature block, which is small enough to read
+    // into memory and parse as the blocking interface does.
+    archive.rewind().await?;
+    let mut header = Vec::new();
+    (&mut *archive)
+        .take(MAX_INFO_SIZE)
+        .read_to_end(&mut header)
+        .await?;
+    info_from_offsets(Cursor::new(header), offset_to_index, offset_to_content)
+}
+
+/// Read the index from a MAR file.
+pub async fn read_index<R>(archive: &mut R) -> Result<Vec<MarItem>>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    read_index_with_options(archive, &ReadOptions::new()).await
+}
+
+/// Read the index from a MAR file.
+///
+/// Behaves as `read_index` with the given options.
+pub async fn read_index_with_options<R>(
+    archive: &mut R,
+    options: &ReadOptions,
+) -> Result<Vec<MarItem>>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    let offset_to_index = read_header(archive).await?;
+    let size_of_index = seek_index(archive, offset_to_index).await?;
+
+    let mut index = vec![0; size_of_index as usize];
+    archive.read_exact(&mut index).await.map_err(truncated)?;
+    parse_index(&index, options)
+}
+
+/// Checks the magic bytes at the start of a MAR file and returns the offset to the index.
+async fn read_header<R>(archive: &mut R) -> Result<u32>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    archive.rewind().await?;
+
+    let mut header = Vec::with_capacity(MAR_ID_SIZE + 4);
+    (&mut *archive)
+        .take(MAR_ID_SIZE as u64 + 4)
+        .read_to_end(&mut header)
+        .await?;
+    parse_header(&header)
+}
+
+/// Checks that the index of a MAR file lies within the file and returns its size, leaving the
+/// stream positioned at the first entry.
+async fn seek_index<R>(archive: &mut R, offset_to_index: u32) -> Result<u32>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    let file_size = archive.seek(SeekFrom::End(0)).await?;
+    check_index_bounds(offset_to_index, 0, file_size)?;
+
+    archive
+        .seek(SeekFrom::Start(offset_to_index as u64))
+        .await?;
+    let size_of_index = archive.read_u32().await.map_err(truncated)?;
+    check_index_bounds(offset_to_index, size_of_index, file_size)?;
+    Ok(size_of_index)
+}
+
+/// A high level interface to read the contents of a mar file asynchronously.
+pub struct AsyncMar<R> {
+    info: MarFileInfo,
+    index: Index,
+    buffer: R,
+}
+
+impl<R> AsyncMar<R>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    /// Creates an AsyncMar instance from any seekable readable.
+    pub async fn from_buffer(buffer: R) -> Result<AsyncMar<R>> {
+        Self::from_buffer_with_options(buffer, &ReadOptions::new()).await
+    }
+
+    /// Creates an AsyncMar instance from any seekable readable.
+    ///
+    /// Behaves as `from_buffer` with the given options.
+    pub async fn from_buffer_with_options(
+        mut buffer: R,
+        options: &ReadOptions,
+    ) -> Result<AsyncMar<R>> {
+        let info = get_info(&mut buffer).await?;
+        let index = Index::new(read_index_with_options(&mut buffer, options).await?);
+
+        Ok(AsyncMar {
+            info,
+            index,
+            buffer,
+        })
+    }
+
+    /// Reads the contents of a file from this mar.
+    pub async fn read(&mut self, item: &MarItem) -> Result<AsyncCompressedRead<'_, R>> {
+        check_span(&self.info, item)?;
+        self.buffer
+            .seek(SeekFrom::Start(item.offset as u64))
+            .await?;
+
+        let mut header = [0_u8; 6];
+        self.buffer
+            .read_exact(&mut header[..header_len(item.length as u64)])
+            .await?;
+        self.buffer
+            .seek(SeekFrom::Start(item.offset as u64))
+            .await?;
+
+        Ok(AsyncCompressedRead::new(
+            &header,
+            (&mut self.buffer).take(item.length as u64),
+        )?)
+    }
+
+    /// Reads the stored bytes of a file from this mar without decompressing them.
+    pub async fn read_raw(&mut self, item: &MarItem) -> Result<Take<&mut R>> {
+        check_span(&self.info, item)?;
+        self.buffer
+            .seek(SeekFrom::Start(item.offset as u64))
+            .await?;
+        Ok((&mut self.buffer).take(item.length as u64))
+    }
+
+    /// Reads the contents of the named file from this mar, if it exists.
+    pub async fn read_by_name(&mut self, name: &str) -> Result<Option<AsyncCompressedRead<'_, R>>> {
+        match self.entry(name).cloned() {
+            Some(item) => Ok(Some(self.read(&item).await?)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<R> AsyncMar<R> {
+    /// Returns the metadata about this mar.
+    pub fn info(&self) -> &MarFileInfo {
+        &self.info
+    }
+
+    /// Returns the entries in this mar, in the order they appear in the index.
+    pub fn files(&self) -> &[MarItem] {
+        self.index.items()
+    }
+
+    /// Returns the entry with the given name, if there is one.
+    pub fn entry(&self, name: &str) -> Option<&MarItem> {
+        self.index.get(name)
+    }
+
+    /// Returns true if this mar has an entry with the given name.
+    pub fn contains(&self, name: &str) -> bool {
+        self.index.contains(name)
+    }
+
+    /// Returns the number of entries in this mar.
+    pub fn len(&self) -> usize {
+        self.index.items().len()
+    }
+
+    /// Returns true if this mar has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.index.items().is_empty()
+    }
+
+    /// Returns an Iterator over the entries in this mar, ordered by name.
+    pub fn sorted_entries(&self) -> impl Iterator<Item = &MarItem> {
+        self.index.sorted()
+    }
+}
+
+enum Decoder {
+    None,
+    Bz2(bzip2::Decompress),
+    Xz(xz::stream::Stream),
+}
+
+impl Decoder {
+    /// Decompresses from `input` into `output`, returning the bytes consumed and produced and
+    /// whether the end of the compressed stream was reached.
+    fn process(&mut self, input: &[u8], output: &mut [u8]) -> io::Result<(usize, usize, bool)> {
+        match self {
+            Decoder::None => {
+                let count = input.len().min(output.len());
+                output[..count].copy_from_slice(&input[..count]);
+                Ok((count, count, false))
+            }
+            Decoder::Bz2(decompress) => {
+                let (before_in, before_out) = (decompress.total_in(), decompress.total_out());
+                let status = decompress
+                    .decompress(input, output)
+                    .map_err(|e| decompression_error(io::Error::new(ErrorKind::InvalidData, e)))?;
+                Ok((
+                    (decompress.total_in() - before_in) as usize,
+                    (decompress.total_out() - before_out) as usize,
+                    status == bzip2::Status::StreamEnd,
+                ))
+            }
+            Decoder::Xz(stream) => {
+                let (before_in, before_out) = (stream.total_in(), stream.total_out());
+                let status = stream
+                    .process(input, output, xz::stream::Action::Run)
+                    .map_err(|e| decompression_error(e.into()))?;
+                Ok((
+                    (stream.total_in() - before_in) as usize,
+                    (stream.total_out() - before_out) as usize,
+                    status == xz::stream::Status::StreamEnd,
+                ))
+            }
+        }
+    }
+}
+
+/// A decompressing wrapper around the stored data of an entry.
+pub struct AsyncCompressedRead<'a, R> {
+    inner: BufReader<Take<&'a mut R>>,
+    decoder: Decoder,
+    finished: bool,
+}
+
+impl<'a, R> AsyncCompressedRead<'a, R>
+where
+    R: AsyncRead + Unpin,
+{
+    /// Detects the type of compression from the first bytes of the data.
+    fn new(header: &[u8; 6], inner: Take<&'a mut R>) -> io::Result<AsyncCompressedRead<'a, R>> {
+        let decoder = if header[0..3] == BZ2_HEADER {
+            Decoder::Bz2(bzip2::Decompress::new(false))
+        } else if *header == XZ_HEADER {
+            Decoder::Xz(xz::stream::Stream::new_stream_decoder(u64::MAX, 0)?)
+        } else {
+            Decoder::None
+        };
+
+        Ok(AsyncCompressedRead {
+            inner: BufReader::new(inner),
+            decoder,
+            finished: false,
+        })
+    }
+}
+
+impl<'a, R> AsyncRead for AsyncCompressedRead<'a, R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.finished || buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        loop {
+            let input = ready!(Pin::new(&mut this.inner).poll_fill_buf(cx))?;
+            if input.is_empty() {
+                // The entry's data is exhausted, which is only expected for uncompressed data.
+                this.finished = true;
+                return match this.decoder {
+                    Decoder::None => Poll::Ready(Ok(())),
+                    _ => Poll::Ready(Err(decompression_error(ErrorKind::UnexpectedEof.into()))),
+                };
+            }
+
+            let (consumed, produced, finished) =
+                this.decoder.process(input, buf.initialize_unfilled())?;
+            Pin::new(&mut this.inner).consume(consumed);
+            buf.advance(produced);
+
+            if finished {
+                this.finished = true;
+                return Poll::Ready(Ok(()));
+            }
+            if produced > 0 {
+                return Poll::Ready(Ok(()));
+            }
+            if consumed == 0 {
+                return Poll::Ready(Err(decompression_error(ErrorKind::UnexpectedEof.into())));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::compression::CompressionType;
+    use crate::testing::{self, build, build_with_signature_slot, bz2};
+    use crate::{read, MarError};
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    /// Builds an archive with a product information block and space for a signature.
+    fn archive() -> Vec<u8> {
+        let mut builder = testing::builder();
+        builder.add_entry("a.txt", 0o644, &b"hello"[..]);
+        builder.add_entry("b/c.txt", 0o755, &b"world"[..]);
+        build_with_signature_slot(builder)
+    }
+
+    #[test]
+    fn matches_blocking_reader() {
+        let data = archive();
+        let info = read::get_info(Cursor::new(&data)).unwrap();
+        let index = read::read_index(Cursor::new(&data)).unwrap();
+
+        block_on(async {
+            let mut mar = AsyncMar::from_buffer(Cursor::new(&data)).await.unwrap();
+            assert_eq!(format!("{:?}", mar.info()), format!("{:?}", info));
+            assert_eq!(format!("{:?}", mar.files()), format!("{:?}", index));
+
+            let mut content = Vec::new();
+            let mut reader = mar.read_by_name("b/c.txt").await.unwrap().unwrap();
+            reader.read_to_end(&mut content).await.unwrap();
+            assert_eq!(content, b"world");
+        });
+    }
+
+    fn content() -> Vec<u8> {
+        (0..20_000)
+            .flat_map(|i| format!("line {}\n", i).into_bytes())
+            .collect()
+    }
+
+    /// Reads an entry a few bytes at a time.
+    async fn read_slowly<R: AsyncRead + AsyncSeek + Unpin>(
+        mar: &mut AsyncMar<R>,
+        name: &str,
+    ) -> io::Result<Vec<u8>> {
+        let mut reader = mar.read_by_name(name).await.unwrap().unwrap();
+        let mut content = Vec::new();
+        let mut buf = [0; 7];
+        loop {
+            match reader.read(&mut buf).await? {
+                0 => return Ok(content),
+                count => content.extend_from_slice(&buf[..count]),
+            }
+        }
+    }
+
+    #[test]
+    fn decompresses_entries() {
+        let content = content();
+        let mut builder = testing::builder();
+        builder.add_compressed_entry("a.xz", 0o644, &content[..], CompressionType::Xz);
+        builder.add_compressed_entry("b.bcj", 0o755, &content[..], CompressionType::XzBcj);
+        builder.add_entry("c.bz2", 0o644, Cursor::new(bz2(&content)));
+        let data = build(builder);
+
+        block_on(async {
+            let mut mar = AsyncMar::from_buffer(Cursor::new(&data)).await.unwrap();
+            for name in ["a.xz", "b.bcj", "c.bz2"] {
+                assert!(
+                    read_slowly(&mut mar, name).await.unwrap() == content,
+                    "{}",
+                    name
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn rejects_truncated_entries() {
+        let content = content();
+        let mut builder = testing::builder();
+        builder.add_compressed_entry("a.xz", 0o644, &content[..], CompressionType::Xz);
+        builder.add_entry("b.bz2", 0o644, Cursor::new(bz2(&content)));
+        let mut data = build(builder);
+
+        // Halve the stored length of each entry.
+        for name in [&b"a.xz\0"[..], b"b.bz2\0"] {
+            let position = data.windows(name.len()).rposition(|w| w == name).unwrap();
+            let field = position - 8..position - 4;
+            let length = u32::from_be_bytes(data[field.clone()].try_into().unwrap());
+            data[field].copy_from_slice(&(length / 2).to_be_bytes());
+        }
+
+        block_on(async {
+            let mut mar = AsyncMar::from_buffer(Cursor::new(&data)).await.unwrap();
+            for name in ["a.xz", "b.bz2"] {
+                let error = read_slowly(&mut mar, name).await.unwrap_err();
+                assert!(
+                    matches!(MarError::from(error), MarError::Malformed(_)),
+                    "{}",
+                    name
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn rejects_bad_archives() {
+        let data = archive();
+        let mut bad_magic = data.clone();
+        bad_magic[0] = b'X';
+        let mut bad_index = data.clone();
+        bad_index[4..8].copy_from_slice(&u32::MAX.to_be_bytes());
+
+        block_on(async {
+            let result = get_info(&mut Cursor::new(&bad_magic)).await;
+            assert!(matches!(result, Err(MarError::BadMagic)));
+            let result = get_info(&mut Cursor::new(&bad_index)).await;
+            assert!(matches!(result, Err(MarError::IndexOutOfBounds { .. })));
+            let result = get_info(&mut Cursor::new(&data[..6])).await;
+            assert!(matches!(result, Err(MarError::Malformed(_))));
+        });
+    }
+}
@@ -2,21 +2,33 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-//! Handles decompressing the file data within the mar.
+//! Handles compressing and decompressing the file data within the mar.
 
-use std::io::{self, ErrorKind, Read, Seek, Take};
+use std::io::{self, ErrorKind, Read, Seek, Take, Write};
 
+use bzip2::read::BzDecoder;
 use xz::read::XzDecoder;
+use xz::stream::{Check, Filters, LzmaOptions, Stream};
+use xz::write::XzEncoder;
 
-const BZ2_HEADER: [u8; 3] = [b'B', b'Z', b'h'];
-const XZ_HEADER: [u8; 6] = [253, b'7', b'z', b'X', b'Z', 0];
+use crate::{MarError, Result};
 
-enum Compression<'a, R>
+pub(crate) const BZ2_HEADER: [u8; 3] = [b'B', b'Z', b'h'];
+pub(crate) const XZ_HEADER: [u8; 6] = [253, b'7', b'z', b'X', b'Z', 0];
+
+/// The LZMA2 preset used by `xz` when no level is given.
+const XZ_PRESET: u32 = 6;
+
+/// File extensions of executables and libraries that benefit from the BCJ filter.
+const EXECUTABLE_EXTENSIONS: &[&str] = &[".exe", ".dll", ".so", ".dylib"];
+
+enum Compression<I>
 where
-    R: Read + Seek,
+    I: Read,
 {
-    None(Take<&'a mut R>),
-    Xz(XzDecoder<Take<&'a mut R>>),
+    None(I),
+    Bz2(BzDecoder<I>),
+    Xz(XzDecoder<I>),
 }
 
 /// A decompressing wrapper around another Read implementation.
@@ -24,7 +36,7 @@ pub struct CompressedRead<'a, R>
 where
     R: Read + Seek,
 {
-    compression: Compression<'a, R>,
+    inner: DecompressedRead<Take<&'a mut R>>,
 }
 
 impl<'a, R> CompressedRead<'a, R>
@@ -33,46 +45,311 @@ where
 {
     /// Creates a decompressing wrapper around the given Read implementation.
     ///
-    /// Attempts to autodetect the type of compression in use, currently XZ is
-    /// the only format supported.
-    pub fn new(inner: &'a mut R, length: u64) -> io::Result<CompressedRead<'a, R>> {
+    /// Attempts to autodetect the type of compression in use, currently XZ and
+    /// BZ2 are supported.
+    pub fn new(inner: &'a mut R, length: u64) -> Result<CompressedRead<'a, R>> {
         let position = inner.stream_position()?;
 
         let mut header = [0_u8; 6];
-
-        if length > 6 {
-            inner.read_exact(&mut header)?;
-        } else if length > 3 {
-            inner.read_exact(&mut header[0..3])?;
-        }
+        inner.read_exact(&mut header[..header_len(length)])?;
 
         inner.seek(io::SeekFrom::Start(position))?;
 
-        if header[0..3] == BZ2_HEADER {
-            Err(io::Error::new(
-                ErrorKind::InvalidData,
-                "BZ2 compression not yet supported.",
-            ))
-        } else if header == XZ_HEADER {
-            Ok(Self {
-                compression: Compression::Xz(XzDecoder::new(inner.take(length))),
-            })
-        } else {
-            Ok(Self {
-                compression: Compression::None(inner.take(length)),
-            })
-        }
+        Ok(Self {
+            inner: DecompressedRead::new(&header, inner.take(length)),
+        })
     }
 }
 
 impl<'a, R> Read for CompressedRead<'a, R>
 where
     R: Read + Seek,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+/// Returns how many bytes at the start of an entry of the given length are used to detect its
+/// compression.
+pub(crate) fn header_len(length: u64) -> usize {
+    if length > 6 {
+        6
+    } else if length > 3 {
+        3
+    } else {
+        0
+    }
+}
+
+/// Returns the bytes used to detect the compression of an entry's stored data.
+pub(crate) fn compression_header(data: &[u8]) -> [u8; 6] {
+    let mut header = [0_u8; 6];
+    let len = header_len(data.len() as u64);
+    header[..len].copy_from_slice(&data[..len]);
+    header
+}
+
+/// Returns true if the stored data of an entry is compressed.
+pub(crate) fn is_compressed(data: &[u8]) -> bool {
+    let header = compression_header(data);
+    header[0..3] == BZ2_HEADER || header == XZ_HEADER
+}
+
+/// A decompressing wrapper around a Read implementation that yields exactly the stored data of
+/// an entry.
+pub struct DecompressedRead<I>
+where
+    I: Read,
+{
+    compression: Compression<I>,
+}
+
+impl<I> DecompressedRead<I>
+where
+    I: Read,
+{
+    /// Creates a decompressing wrapper given the first bytes of the data, which are used to
+    /// detect the type of compression. Unused bytes of `header` must be zero.
+    pub(crate) fn new(header: &[u8; 6], inner: I) -> DecompressedRead<I> {
+        let compression = if header[0..3] == BZ2_HEADER {
+            Compression::Bz2(BzDecoder::new(inner))
+        } else if *header == XZ_HEADER {
+            Compression::Xz(XzDecoder::new(inner))
+        } else {
+            Compression::None(inner)
+        };
+
+        DecompressedRead { compression }
+    }
+}
+
+impl<I> Read for DecompressedRead<I>
+where
+    I: Read,
 {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         match self.compression {
             Compression::None(ref mut inner) => inner.read(buf),
-            Compression::Xz(ref mut inner) => inner.read(buf),
+            Compression::Bz2(ref mut inner) => inner.read(buf).map_err(decompression_error),
+            Compression::Xz(ref mut inner) => inner.read(buf).map_err(decompression_error),
+        }
+    }
+}
+
+/// Identifies decoder errors caused by the compressed data rather than the underlying reader.
+pub(crate) fn decompression_error(error: io::Error) -> io::Error {
+    let corrupt = || MarError::Malformed("Compressed data is corrupt".to_owned()).into();
+
+    if let Some(inner) = error.get_ref() {
+        if let Some(xz_error) = inner.downcast_ref::<xz::stream::Error>() {
+            return match xz_error {
+                xz::stream::Error::Options | xz::stream::Error::UnsupportedCheck => {
+                    MarError::UnsupportedCompression.into()
+                }
+                xz::stream::Error::Data | xz::stream::Error::Format => corrupt(),
+                _ => error,
+            };
+        }
+        if inner.is::<bzip2::Error>() {
+            return corrupt();
+        }
+    }
+
+    // The entry's data lies within the file so running out means the stream is incomplete.
+    if error.kind() == ErrorKind::UnexpectedEof {
+        corrupt()
+    } else {
+        error
+    }
+}
+
+/// The compression to use when writing file data to a mar.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompressionType {
+    /// The data is stored uncompressed.
+    None,
+    /// XZ compression using LZMA2 with a CRC64 check.
+    Xz,
+    /// XZ compression using the x86 BCJ filter followed by LZMA2 with a CRC64 check, as used by
+    /// Mozilla's update packaging scripts for executables and libraries.
+    XzBcj,
+}
+
+impl CompressionType {
+    /// Chooses the compression Mozilla's update packaging would use for a file.
+    ///
+    /// Executables and libraries use the BCJ filter, everything else uses plain XZ. A file is
+    /// treated as one if its name ends in one of the usual extensions, including versioned shared
+    /// libraries such as `libfoo.so.1.2`, or if `flags` has the owner execute bit set. Only the
+    /// final component of `name` is considered.
+    pub fn for_file(name: &str, flags: u32) -> CompressionType {
+        let file_name = name.rsplit('/').next().unwrap_or(name);
+        let is_executable = flags & 0o100 != 0
+            || EXECUTABLE_EXTENSIONS
+                .iter()
+                .any(|ext| file_name.ends_with(ext))
+            || is_versioned_library(file_name);
+
+        if is_executable {
+            CompressionType::XzBcj
+        } else {
+            CompressionType::Xz
+        }
+    }
+}
+
+/// Returns true for names like `libfoo.so.1` where `.so.` is followed only by version numbers.
+fn is_versioned_library(file_name: &str) -> bool {
+    file_name.match_indices(".so.").any(|(i, _)| {
+        let version = &file_name[i + 4..];
+        !version.is_empty() && version.bytes().all(|b| b.is_ascii_digit() || b == b'.')
+    })
+}
+
+enum Compressor<W>
+where
+    W: Write,
+{
+    None(W),
+    Xz(XzEncoder<W>),
+}
+
+/// A compressing wrapper around another Write implementation.
+pub struct CompressedWrite<W>
+where
+    W: Write,
+{
+    compressor: Compressor<W>,
+}
+
+impl<W> CompressedWrite<W>
+where
+    W: Write,
+{
+    /// Creates a compressing wrapper around the given Write implementation.
+    pub fn new(inner: W, compression: CompressionType) -> Result<CompressedWrite<W>> {
+        let compressor = match compression {
+            CompressionType::None => Compressor::None(inner),
+            CompressionType::Xz | CompressionType::XzBcj => {
+                let stream = xz_stream(compression == CompressionType::XzBcj)
+                    .map_err(|_| MarError::UnsupportedCompression)?;
+                Compressor::Xz(XzEncoder::new_stream(inner, stream))
+            }
+        };
+
+        Ok(Self { compressor })
+    }
+
+    /// Finishes the compressed stream and returns the inner Write implementation.
+    pub fn finish(self) -> io::Result<W> {
+        match self.compressor {
+            Compressor::None(mut inner) => {
+                inner.flush()?;
+                Ok(inner)
+            }
+            Compressor::Xz(inner) => inner.finish(),
+        }
+    }
+}
+
+impl<W> Write for CompressedWrite<W>
+where
+    W: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.compressor {
+            Compressor::None(ref mut inner) => inner.write(buf),
+            Compressor::Xz(ref mut inner) => inner.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.compressor {
+            Compressor::None(ref mut inner) => inner.flush(),
+            Compressor::Xz(ref mut inner) => inner.flush(),
         }
     }
 }
+
+/// Creates an XZ encoder matching `xz [--x86] --lzma2 --format=xz --check=crc64`.
+fn xz_stream(bcj: bool) -> std::result::Result<Stream, xz::stream::Error> {
+    let mut filters = Filters::new();
+    if bcj {
+        filters.x86();
+    }
+    filters.lzma2(&LzmaOptions::new_preset(XZ_PRESET)?);
+
+    Stream::new_stream_encoder(&filters, Check::Crc64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::testing::{build, bz2, read_entry};
+    use crate::write::MarBuilder;
+    use crate::Mar;
+
+    #[test]
+    fn compression_for_file() {
+        let bcj = |name, flags| CompressionType::for_file(name, flags) == CompressionType::XzBcj;
+
+        assert!(bcj("firefox.exe", 0o644));
+        assert!(bcj("xul.dll", 0o644));
+        assert!(bcj("libxul.so", 0o644));
+        assert!(bcj("libnss3.so.1.2", 0o644));
+        assert!(bcj("libmozglue.dylib", 0o644));
+        assert!(bcj("firefox", 0o755));
+
+        assert!(!bcj("readme.txt", 0o644));
+        assert!(!bcj("omni.ja", 0o644));
+        assert!(!bcj("notes.so.txt", 0o644));
+        assert!(!bcj("foo.dll.bak", 0o644));
+        assert!(!bcj("lib.so.d/readme", 0o644));
+        assert!(!bcj("firefox.exe/readme", 0o644));
+        assert!(!bcj("script", 0o655));
+    }
+
+    const CONTENT: &[u8] = b"The quick brown fox jumps over the lazy dog.\n";
+
+    fn decompress(data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut cursor = Cursor::new(data);
+        let mut output = Vec::new();
+        CompressedRead::new(&mut cursor, data.len() as u64)?.read_to_end(&mut output)?;
+        Ok(output)
+    }
+
+    #[test]
+    fn bz2_round_trip() {
+        assert_eq!(decompress(&bz2(CONTENT)).unwrap(), CONTENT);
+    }
+
+    #[test]
+    fn bz2_entry_round_trip() {
+        let mut builder = MarBuilder::new();
+        builder.add_entry("a.txt", 0o644, Cursor::new(bz2(CONTENT)));
+
+        let mut mar = Mar::from_buffer(Cursor::new(build(builder))).unwrap();
+        assert_eq!(read_entry(&mut mar, "a.txt"), CONTENT);
+    }
+
+    #[test]
+    fn bz2_corrupt_stream() {
+        let mut data = bz2(CONTENT);
+        let middle = data.len() / 2;
+        data[middle..].iter_mut().for_each(|b| *b = !*b);
+
+        let error = decompress(&data).unwrap_err();
+        assert!(matches!(MarError::from(error), MarError::Malformed(_)));
+    }
+
+    #[test]
+    fn bz2_truncated_stream() {
+        let data = bz2(CONTENT);
+
+        let error = decompress(&data[..data.len() - 4]).unwrap_err();
+        assert!(matches!(MarError::from(error), MarError::Malformed(_)));
+    }
+}
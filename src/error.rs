@@ -0,0 +1,203 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The error type for MAR operations.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, ErrorKind};
+
+use crate::manifest::ManifestError;
+
+/// A specialized `Result` type for MAR operations.
+pub type Result<T> = std::result::Result<T, MarError>;
+
+/// An error reading, writing, signing or extracting a MAR file.
+///
+/// Every variant except `Io` means the archive or the arguments given are invalid, so retrying
+/// will not help.
+///
+/// Readers returned by this crate carry a `MarError` inside the `io::Error` where one applies,
+/// converting the `io::Error` back with `MarError::from` recovers it.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum MarError {
+    /// The file does not start with the MAR magic bytes.
+    BadMagic,
+    /// The index lies outside of the file.
+    IndexOutOfBounds {
+        /// The offset that is out of bounds.
+        offset: u64,
+    },
+    /// A signature in the signature block extends past the end of the file.
+    SignatureOutOfBounds {
+        /// The offset of the signature.
+        offset: u64,
+    },
+    /// An additional block extends past the end of the file.
+    AdditionalBlockOutOfBounds {
+        /// The offset of the block.
+        offset: u64,
+    },
+    /// The data of an entry extends past the start of the index.
+    EntryOverlapsIndex {
+        /// The name of the entry.
+        name: String,
+    },
+    /// The data of an entry starts within the header, signature block or additional blocks.
+    EntryOverlapsHeader {
+        /// The name of the entry.
+        name: String,
+    },
+    /// The data of two entries overlap.
+    EntriesOverlap {
+        /// The name of the entry that comes first in the file.
+        first: String,
+        /// The name of the entry that overlaps it.
+        second: String,
+    },
+    /// The name of an entry is empty or too long.
+    InvalidName {
+        /// The name of the entry.
+        name: String,
+        /// Why the name is invalid.
+        reason: &'static str,
+    },
+    /// An entry uses a compression format or option that is not supported.
+    UnsupportedCompression,
+    /// The name of an entry is not valid UTF-8.
+    InvalidUtf8Name {
+        /// The raw bytes of the name.
+        bytes: Vec<u8>,
+    },
+    /// The name of an entry would place it outside of the destination directory.
+    UnsafeName {
+        /// The name of the entry.
+        name: String,
+        /// Why the name is unsafe.
+        reason: &'static str,
+    },
+    /// The archive is not signed.
+    Unsigned,
+    /// None of the signatures could be verified by a key.
+    SignatureInvalid,
+    /// No keys were given to sign with or to verify the signatures with.
+    NoKeys,
+    /// The update manifest could not be parsed.
+    Manifest(ManifestError),
+    /// A key could not be loaded or used.
+    InvalidKey(String),
+    /// A patch is malformed or does not apply to the file it patches.
+    InvalidPatch(String),
+    /// An argument is invalid, such as a name or product information that cannot be stored.
+    InvalidInput(String),
+    /// The archive is malformed in some other way.
+    Malformed(String),
+    /// An error from the underlying reader or writer.
+    Io(io::Error),
+}
+
+impl fmt::Display for MarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MarError::BadMagic => write!(f, "Not a MAR file (invalid bytes at start of file)"),
+            MarError::IndexOutOfBounds { offset } => {
+                write!(f, "Offset {} is beyond the end of the file", offset)
+            }
+            MarError::SignatureOutOfBounds { offset } => {
+                write!(
+                    f,
+                    "Signature at offset {} is beyond the end of the file",
+                    offset
+                )
+            }
+            MarError::AdditionalBlockOutOfBounds { offset } => write!(
+                f,
+                "Additional block at offset {} is beyond the end of the file",
+                offset
+            ),
+            MarError::EntryOverlapsIndex { name } => {
+                write!(f, "Data for {:?} extends past the end of the content", name)
+            }
+            MarError::EntryOverlapsHeader { name } => write!(
+                f,
+                "Data for {:?} overlaps the header or signature block",
+                name
+            ),
+            MarError::EntriesOverlap { first, second } => {
+                write!(f, "Data for {:?} overlaps data for {:?}", second, first)
+            }
+            MarError::InvalidName { name, reason } => {
+                write!(f, "Invalid entry name {:?}: the name {}", name, reason)
+            }
+            MarError::UnsupportedCompression => write!(f, "Unsupported compression"),
+            MarError::InvalidUtf8Name { bytes } => write!(
+                f,
+                "Filename is not UTF-8: {:?}",
+                String::from_utf8_lossy(bytes)
+            ),
+            MarError::UnsafeName { name, reason } => {
+                write!(f, "Refusing to extract {:?}: the name {}", name, reason)
+            }
+            MarError::Unsigned => write!(f, "MAR file is not signed"),
+            MarError::SignatureInvalid => write!(f, "Signature verification failed"),
+            MarError::NoKeys => write!(f, "No keys given to sign or verify with"),
+            MarError::Manifest(error) => write!(f, "{}", error),
+            MarError::InvalidKey(reason) => write!(f, "Invalid key: {}", reason),
+            MarError::InvalidPatch(reason) => write!(f, "Invalid patch: {}", reason),
+            MarError::InvalidInput(reason) => f.write_str(reason),
+            MarError::Malformed(reason) => f.write_str(reason),
+            MarError::Io(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl Error for MarError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MarError::Manifest(error) => Some(error),
+            MarError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MarError {
+    fn from(error: io::Error) -> Self {
+        let inner = error.get_ref();
+        if inner.is_some_and(|inner| inner.is::<MarError>()) {
+            *error.into_inner().unwrap().downcast().unwrap()
+        } else if inner.is_some_and(|inner| inner.is::<ManifestError>()) {
+            MarError::Manifest(*error.into_inner().unwrap().downcast().unwrap())
+        } else {
+            MarError::Io(error)
+        }
+    }
+}
+
+impl From<MarError> for io::Error {
+    fn from(error: MarError) -> Self {
+        match error {
+            MarError::Io(error) => error,
+            MarError::UnsupportedCompression => io::Error::new(ErrorKind::Unsupported, error),
+            MarError::InvalidInput(_) => io::Error::new(ErrorKind::InvalidInput, error),
+            error => io::Error::new(ErrorKind::InvalidData, error),
+        }
+    }
+}
+
+impl From<ManifestError> for MarError {
+    fn from(error: ManifestError) -> Self {
+        MarError::Manifest(error)
+    }
+}
+
+/// Treats running out of data while parsing the archive's structure as a malformed archive.
+pub(crate) fn truncated(error: io::Error) -> MarError {
+    if error.kind() == ErrorKind::UnexpectedEof {
+        MarError::Malformed("Unexpected end of file".to_owned())
+    } else {
+        MarError::from(error)
+    }
+}
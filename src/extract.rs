@@ -4,54 +4,626 @@
 
 //! Extracting archives to the filesystem.
 
-use crate::read::read_index;
-use std::fs::{self, File, OpenOptions};
-use std::io::{self, BufReader, ErrorKind, Read, Seek, SeekFrom};
+use crate::read::{check_span, ReadOptions};
+use crate::shared::{ReadAt, SharedMar};
+use crate::{Mar, MarError, MarItem, Result};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Read, Seek};
 #[cfg(unix)]
 use std::os::unix::fs::OpenOptionsExt;
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+/// Options controlling how files are extracted.
+#[derive(Clone, Debug, Default)]
+pub struct ExtractOptions {
+    raw: bool,
+    threads: usize,
+    non_utf8_names: bool,
+}
+
+impl ExtractOptions {
+    /// Creates the default options, which decompress every file.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets whether to write the bytes stored in the archive rather than decompressing them.
+    pub fn raw(&mut self, raw: bool) -> &mut Self {
+        self.raw = raw;
+        self
+    }
+
+    /// Sets how many threads `extract_shared` uses, 0 (the default) uses one per available CPU.
+    pub fn threads(&mut self, threads: usize) -> &mut Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Sets whether to extract entries whose names are not valid UTF-8 when opening an archive
+    /// from a path. On Unix such names are used as raw bytes, elsewhere they are rejected.
+    pub fn non_utf8_names(&mut self, non_utf8_names: bool) -> &mut Self {
+        self.non_utf8_names = non_utf8_names;
+        self
+    }
+}
+
+/// Progress of an extraction, reported after each file is written.
+#[derive(Clone, Debug)]
+pub struct Progress<'a> {
+    /// Number of files written so far.
+    pub entries_done: usize,
+    /// Number of files to write. Entries replaced by a later entry with the same path are not
+    /// counted.
+    pub total_entries: usize,
+    /// Number of bytes written so far.
+    pub bytes_written: u64,
+    /// Name of the file that was just written.
+    pub name: &'a str,
+}
 
 /// Extract all the files from the specified archive to the current directory.
+pub fn extract<P: AsRef<Path>>(path: P) -> Result<()> {
+    extract_to(path, ".")
+}
+
+/// Extract all the files from the specified archive to the `dest` directory.
+///
+/// Entries whose names would place them outside of `dest` are rejected, this includes absolute
+/// paths, `..` components, backslashes and existing symlinks along the path.
+pub fn extract_to<P, D>(path: P, dest: D) -> Result<()>
+where
+    P: AsRef<Path>,
+    D: AsRef<Path>,
+{
+    extract_with_options(path, dest, &ExtractOptions::new())
+}
+
+/// Extract all the files from the specified archive to the `dest` directory.
+///
+/// Behaves as `extract_to` with the given options. Files are extracted in parallel on platforms
+/// that support positional reads, and one at a time elsewhere.
+pub fn extract_with_options<P, D>(path: P, dest: D, options: &ExtractOptions) -> Result<()>
+where
+    P: AsRef<Path>,
+    D: AsRef<Path>,
+{
+    let mut read_options = ReadOptions::new();
+    read_options.non_utf8_names(options.non_utf8_names);
+
+    #[cfg(any(unix, windows))]
+    {
+        let archive = SharedMar::from_path_with_options(path, &read_options)?;
+        extract_shared(&archive, dest, options, |_| {})
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        let mut archive = Mar::from_path_with_options(path, &read_options)?;
+        extract_mar(&mut archive, dest, options)
+    }
+}
+
+/// Extract all the files from an open archive to the `dest` directory.
+pub fn extract_mar<R, D>(archive: &mut Mar<R>, dest: D, options: &ExtractOptions) -> Result<()>
+where
+    R: Read + Seek,
+    D: AsRef<Path>,
+{
+    let dest = dest.as_ref();
+    let index = archive.files().to_vec();
+
+    // Check every entry before writing anything.
+    for item in &index {
+        safe_path(dest, item_path(item)?)?;
+        archive.check_span(item)?;
+    }
+
+    fs::create_dir_all(dest)?;
+    let targets = create_all_parents(dest, &index)?;
+
+    for (item, target) in index.iter().zip(targets) {
+        if options.raw {
+            write_file(&target, item, archive.read_raw(item)?, true)?;
+        } else {
+            write_file(&target, item, archive.read(item)?, false)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Extract all the files from a shared archive to the `dest` directory, writing several files at
+/// once.
 ///
-/// TODO: Accept an output path?
-pub fn extract<P: AsRef<Path>>(path: P) -> io::Result<()> {
-    let mut archive = BufReader::new(File::open(path)?);
-    let index = read_index(&mut archive)?;
+/// `progress` is called after each file is written. Calls never overlap and the counts they
+/// report only increase. If several entries have the same path only the last is written, as
+/// when extracting sequentially. If extracting a file fails the remaining files are skipped and
+/// the error is returned.
+pub fn extract_shared<R, D, F>(
+    archive: &SharedMar<R>,
+    dest: D,
+    options: &ExtractOptions,
+    progress: F,
+) -> Result<()>
+where
+    R: ReadAt + Sync,
+    D: AsRef<Path>,
+    F: Fn(&Progress) + Sync,
+{
+    let dest = dest.as_ref();
+    let index = archive.files();
 
+    // Check every entry before writing anything.
     for item in index {
-        // Read data from the archive.
-        archive.seek(SeekFrom::Start(item.offset as u64))?;
-        let mut data = archive.by_ref().take(item.length as u64);
-
-        // Create parent directories if necessary.
-        // TODO: Consolidate duplicate parents to make this more efficient?
-        // TODO: Prevent directory traversal?
-        {
-            let dir = Path::new(&item.name).parent().ok_or_else(|| {
-                io::Error::new(ErrorKind::InvalidData, "File name must not be empty")
-            })?;
-            fs::create_dir_all(dir)?;
-        }
-
-        // Write data to a file.
-        let mut options = OpenOptions::new();
-        options.write(true);
-        options.create(true);
-        #[cfg(unix)]
-        {
-            options.mode(item.flags);
-        }
-        let mut file = options.open(item.name)?;
-
-        // Copy the data.
-        let bytes_written = io::copy(&mut data, &mut file)?;
-        if bytes_written != item.length as u64 {
-            return Err(io::Error::new(
-                ErrorKind::UnexpectedEof,
-                "Unexpected end of file",
+        safe_path(dest, item_path(item)?)?;
+        check_span(archive.info(), item)?;
+    }
+
+    fs::create_dir_all(dest)?;
+    let targets = create_all_parents(dest, index)?;
+
+    // Writing the same file from two workers could interleave their contents, so only the last
+    // entry for each path is written.
+    let last: HashMap<&PathBuf, usize> = targets
+        .iter()
+        .enumerate()
+        .map(|(position, target)| (target, position))
+        .collect();
+    let work: Vec<(&MarItem, &PathBuf)> = index
+        .iter()
+        .zip(&targets)
+        .enumerate()
+        .filter(|(position, (_, target))| last[target] == *position)
+        .map(|(_, entry)| entry)
+        .collect();
+
+    let threads = match options.threads {
+        0 => thread::available_parallelism().map_or(1, |count| count.get()),
+        count => count,
+    }
+    .min(work.len());
+
+    // Workers take the next file to write from `next` until none remain or one of them fails.
+    let next = AtomicUsize::new(0);
+    let done = Mutex::new((0, 0));
+    let extract_next = || -> Result<()> {
+        loop {
+            let position = next.fetch_add(1, Ordering::Relaxed);
+            let Some(&(item, target)) = work.get(position) else {
+                return Ok(());
+            };
+
+            let written = match extract_item(archive, item, target, options.raw) {
+                Ok(written) => written,
+                Err(e) => {
+                    next.store(work.len(), Ordering::Relaxed);
+                    return Err(e);
+                }
+            };
+
+            let mut done = done.lock().unwrap_or_else(|e| e.into_inner());
+            done.0 += 1;
+            done.1 += written;
+            progress(&Progress {
+                entries_done: done.0,
+                total_entries: work.len(),
+                bytes_written: done.1,
+                name: &item.name,
+            });
+        }
+    };
+
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads).map(|_| scope.spawn(extract_next)).collect();
+        workers
+            .into_iter()
+            .map(|worker| {
+                worker
+                    .join()
+                    .unwrap_or_else(|e| std::panic::resume_unwind(e))
+            })
+            .fold(Ok(()), Result::and)
+    })
+}
+
+/// Creates the parent directories of every entry, checking each directory only once, and
+/// returns the paths to write the entries to.
+fn create_all_parents(dest: &Path, index: &[MarItem]) -> io::Result<Vec<PathBuf>> {
+    let mut checked = HashSet::new();
+    index
+        .iter()
+        .map(|item| create_parents_checked(dest, item_path(item)?, &mut checked))
+        .collect()
+}
+
+/// Returns the name of an entry as a relative path.
+///
+/// On Unix the raw bytes of the name are used so names that are not UTF-8 are extracted exactly.
+/// Elsewhere such names are rejected.
+fn item_path(item: &MarItem) -> io::Result<&Path> {
+    #[cfg(unix)]
+    {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        Ok(Path::new(OsStr::from_bytes(item.name_bytes())))
+    }
+    #[cfg(not(unix))]
+    {
+        if !item.is_utf8_name() {
+            return Err(MarError::InvalidUtf8Name {
+                bytes: item.name_bytes().to_vec(),
+            }
+            .into());
+        }
+        Ok(Path::new(&item.name))
+    }
+}
+
+/// Writes a single entry from a shared archive, returning the number of bytes written.
+fn extract_item<R: ReadAt>(
+    archive: &SharedMar<R>,
+    item: &MarItem,
+    target: &Path,
+    raw: bool,
+) -> Result<u64> {
+    if raw {
+        write_file(target, item, archive.read_raw(item)?, true)
+    } else {
+        write_file(target, item, archive.read(item)?, false)
+    }
+}
+
+/// Writes the data of an entry to a new file, returning the number of bytes written.
+fn write_file<R: Read>(target: &Path, item: &MarItem, mut data: R, raw: bool) -> Result<u64> {
+    let mut file = create_file(target, item.flags)?;
+    let bytes_written = io::copy(&mut data, &mut file)?;
+    if raw && bytes_written != item.length as u64 {
+        return Err(MarError::Malformed("Unexpected end of file".to_owned()));
+    }
+    Ok(bytes_written)
+}
+
+/// Creates or truncates a file to extract an entry to.
+///
+/// A new file is given the permission bits of the entry's mode, subject to the umask. The
+/// setuid, setgid and sticky bits are never set, and an existing file keeps its mode.
+pub(crate) fn create_file(path: &Path, flags: u32) -> io::Result<fs::File> {
+    let mut options = OpenOptions::new();
+    options.write(true);
+    options.create(true);
+    options.truncate(true);
+    #[cfg(unix)]
+    {
+        options.mode(flags & 0o777);
+    }
+    #[cfg(not(unix))]
+    let _ = flags;
+
+    options.open(path)
+}
+
+/// Resolves an entry name to a path within `dest`.
+///
+/// Only plain relative paths using `/` as a separator are accepted.
+pub(crate) fn safe_path(dest: &Path, name: impl AsRef<Path>) -> io::Result<PathBuf> {
+    let name = name.as_ref();
+    let bytes = name.as_os_str().as_encoded_bytes();
+    if bytes.contains(&b'\\') {
+        return Err(unsafe_name(name, "contains a backslash"));
+    }
+    // Drive prefixes are only parsed as such on Windows but must be rejected everywhere.
+    if bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' {
+        return Err(unsafe_name(name, "has a drive prefix"));
+    }
+
+    let mut path = dest.to_owned();
+    let mut has_components = false;
+    for component in name.components() {
+        match component {
+            Component::Normal(part) => {
+                path.push(part);
+                has_components = true;
+            }
+            Component::CurDir => {}
+            Component::ParentDir => return Err(unsafe_name(name, "contains a `..` component")),
+            Component::RootDir | Component::Prefix(_) => {
+                return Err(unsafe_name(name, "is an absolute path"))
+            }
+        }
+    }
+
+    if !has_components {
+        return Err(unsafe_name(name, "is empty"));
+    }
+    Ok(path)
+}
+
+/// Creates the parent directories of an entry within `dest`, returning the entry's path.
+///
+/// Fails if any existing directory along the way, or the entry itself, is a symlink.
+pub(crate) fn create_parents(dest: &Path, name: impl AsRef<Path>) -> io::Result<PathBuf> {
+    create_parents_checked(dest, name.as_ref(), &mut HashSet::new())
+}
+
+/// Behaves as `create_parents` but skips the directories in `checked`, adding those it checks.
+fn create_parents_checked(
+    dest: &Path,
+    name: &Path,
+    checked: &mut HashSet<PathBuf>,
+) -> io::Result<PathBuf> {
+    let target = safe_path(dest, name)?;
+    let relative = target.strip_prefix(dest).unwrap_or(&target);
+
+    let mut path = dest.to_owned();
+    let mut components = relative.components().peekable();
+    while let Some(component) = components.next() {
+        path.push(component);
+        let is_last = components.peek().is_none();
+        if !is_last && checked.contains(&path) {
+            continue;
+        }
+
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                return Err(unsafe_name(name, "passes through a symlink"));
+            }
+            Ok(metadata) if !is_last && !metadata.is_dir() => {
+                return Err(unsafe_name(name, "has a parent that is not a directory"));
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                if !is_last {
+                    fs::create_dir(&path)?;
+                }
+            }
+            Err(e) => return Err(e),
+        }
+
+        if !is_last {
+            checked.insert(path.clone());
+        }
+    }
+
+    Ok(target)
+}
+
+/// Resolves the path of an existing entry within `dest` without creating anything.
+///
+/// Fails if any parent directory along the way is a symlink. The entry itself may be a symlink.
+pub(crate) fn existing_path(dest: &Path, name: impl AsRef<Path>) -> io::Result<PathBuf> {
+    let name = name.as_ref();
+    let target = safe_path(dest, name)?;
+    let relative = target.strip_prefix(dest).unwrap_or(&target);
+
+    let mut path = dest.to_owned();
+    let mut components = relative.components().peekable();
+    while let Some(component) = components.next() {
+        if components.peek().is_none() {
+            break;
+        }
+        path.push(component);
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                return Err(unsafe_name(name, "passes through a symlink"));
+            }
+            _ => {}
+        }
+    }
+
+    Ok(target)
+}
+
+fn unsafe_name(name: &Path, reason: &'static str) -> io::Error {
+    MarError::UnsafeName {
+        name: name.to_string_lossy().into_owned(),
+        reason,
+    }
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::{CompressionType, XZ_HEADER};
+    use crate::testing::{self, build};
+
+    /// Writes an archive to `dir` and returns its path.
+    fn write_archive(dir: &Path, builder: crate::write::MarBuilder<'_>) -> PathBuf {
+        let path = dir.join("test.mar");
+        fs::write(&path, build(builder)).unwrap();
+        path
+    }
+
+    fn unsafe_reason(name: &str) -> Option<&'static str> {
+        match safe_path(Path::new("dest"), name) {
+            Ok(_) => None,
+            Err(error) => match MarError::from(error) {
+                MarError::UnsafeName { reason, .. } => Some(reason),
+                error => panic!("unexpected error {:?}", error),
+            },
+        }
+    }
+
+    #[test]
+    fn safe_path_accepts_relative_names() {
+        assert_eq!(
+            safe_path(Path::new("dest"), "a/./b.txt").unwrap(),
+            Path::new("dest/a/b.txt")
+        );
+    }
+
+    #[test]
+    fn safe_path_rejects_unsafe_names() {
+        for (name, reason) in [
+            ("..", "contains a `..` component"),
+            ("../a", "contains a `..` component"),
+            ("a/../../b", "contains a `..` component"),
+            ("/etc/passwd", "is an absolute path"),
+            ("C:\\Windows", "contains a backslash"),
+            ("C:foo", "has a drive prefix"),
+            ("c:/foo", "has a drive prefix"),
+            ("a\\b", "contains a backslash"),
+            ("", "is empty"),
+            (".", "is empty"),
+            ("./", "is empty"),
+        ] {
+            assert_eq!(unsafe_reason(name), Some(reason), "{:?}", name);
+        }
+    }
+
+    fn compressed_archive(dir: &Path) -> PathBuf {
+        let mut builder = testing::builder();
+        builder.add_compressed_entry("a.txt", 0o644, &b"hello"[..], CompressionType::Xz);
+        builder.add_entry("b/c.txt", 0o644, &b"plain"[..]);
+        write_archive(dir, builder)
+    }
+
+    #[test]
+    fn extract_decompresses() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = compressed_archive(dir.path());
+
+        let dest = dir.path().join("dest");
+        extract_to(&archive, &dest).unwrap();
+        assert_eq!(fs::read(dest.join("a.txt")).unwrap(), b"hello");
+        assert_eq!(fs::read(dest.join("b/c.txt")).unwrap(), b"plain");
+
+        let dest = dir.path().join("sequential");
+        let mut mar = Mar::from_path(&archive).unwrap();
+        extract_mar(&mut mar, &dest, &ExtractOptions::new()).unwrap();
+        assert_eq!(fs::read(dest.join("a.txt")).unwrap(), b"hello");
+        assert_eq!(fs::read(dest.join("b/c.txt")).unwrap(), b"plain");
+    }
+
+    #[test]
+    fn extract_raw() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = compressed_archive(dir.path());
+        let mut mar = Mar::from_path(&archive).unwrap();
+        let item = mar.entry("a.txt").unwrap().clone();
+        let mut stored = Vec::new();
+        mar.read_raw(&item)
+            .unwrap()
+            .read_to_end(&mut stored)
+            .unwrap();
+        assert!(stored.starts_with(&XZ_HEADER));
+
+        let dest = dir.path().join("dest");
+        extract_with_options(&archive, &dest, ExtractOptions::new().raw(true)).unwrap();
+        assert_eq!(fs::read(dest.join("a.txt")).unwrap(), stored);
+        assert_eq!(fs::read(dest.join("b/c.txt")).unwrap(), b"plain");
+
+        let dest = dir.path().join("sequential");
+        extract_mar(&mut mar, &dest, ExtractOptions::new().raw(true)).unwrap();
+        assert_eq!(fs::read(dest.join("a.txt")).unwrap(), stored);
+    }
+
+    #[test]
+    fn extract_shared_in_parallel() {
+        let contents: Vec<Vec<u8>> = (0..20)
+            .map(|i| format!("file {} ", i).repeat(i * 100).into_bytes())
+            .collect();
+        let mut builder = testing::builder();
+        builder.add_entry("dup.txt", 0o644, &b"first"[..]);
+        for (i, content) in contents.iter().enumerate() {
+            let compression = match i % 3 {
+                0 => CompressionType::None,
+                1 => CompressionType::Xz,
+                _ => CompressionType::XzBcj,
+            };
+            builder.add_compressed_entry(
+                format!("d{}/f{}", i % 4, i),
+                0o644,
+                &content[..],
+                compression,
+            );
+        }
+        builder.add_entry("./dup.txt", 0o644, &b"second"[..]);
+        let archive = SharedMar::from_source(build(builder)).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let reports = Mutex::new(Vec::new());
+        let mut options = ExtractOptions::new();
+        options.threads(4);
+        extract_shared(&archive, dir.path(), &options, |progress| {
+            reports.lock().unwrap().push((
+                progress.entries_done,
+                progress.total_entries,
+                progress.bytes_written,
             ));
+        })
+        .unwrap();
+
+        for (i, content) in contents.iter().enumerate() {
+            let path = dir.path().join(format!("d{}/f{}", i % 4, i));
+            assert_eq!(&fs::read(path).unwrap(), content);
         }
+        assert_eq!(fs::read(dir.path().join("dup.txt")).unwrap(), b"second");
+
+        // The duplicate is only written once.
+        let reports = reports.into_inner().unwrap();
+        let total = contents.len() + 1;
+        let bytes = contents.iter().map(Vec::len).sum::<usize>() + b"second".len();
+        assert_eq!(reports.len(), total);
+        assert!(reports
+            .windows(2)
+            .all(|w| w[0].0 < w[1].0 && w[0].2 <= w[1].2));
+        assert!(reports.iter().all(|report| report.1 == total));
+        assert_eq!(reports.last(), Some(&(total, total, bytes as u64)));
     }
 
-    Ok(())
+    #[cfg(unix)]
+    #[test]
+    fn extract_rejects_symlinked_parents() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut builder = testing::builder();
+        builder.add_entry("a.txt", 0o644, &b"hello"[..]);
+        builder.add_entry("d/f", 0o644, &b"escaped"[..]);
+        let archive = write_archive(dir.path(), builder);
+
+        let dest = dir.path().join("dest");
+        let outside = dir.path().join("outside");
+        fs::create_dir(&dest).unwrap();
+        fs::create_dir(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, dest.join("d")).unwrap();
+
+        match extract_to(&archive, &dest) {
+            Err(MarError::UnsafeName { name, reason }) => {
+                assert_eq!(name, "d/f");
+                assert_eq!(reason, "passes through a symlink");
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(!outside.join("f").exists());
+        assert!(!dest.join("a.txt").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn create_file_drops_special_bits() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        create_file(&path, 0o4755).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7000, 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn create_file_keeps_existing_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        fs::write(&path, b"old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        create_file(&path, 0o755).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
 }
@@ -0,0 +1,472 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Reading MAR files from a URL using HTTP range requests.
+//!
+//! `HttpReader` fetches the file in fixed size blocks as they are needed and caches them, so
+//! listing the contents of a remote archive or reading a single entry downloads only a small
+//! part of it.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
+
+use ureq::Agent;
+
+use crate::{Mar, Result};
+
+/// The default size of each block fetched from the server.
+const DEFAULT_BLOCK_SIZE: u64 = 64 * 1024;
+
+/// The default number of blocks kept in the cache.
+const DEFAULT_CACHED_BLOCKS: usize = 64;
+
+/// Options controlling how a MAR file is fetched over HTTP.
+#[derive(Clone, Debug)]
+pub struct HttpOptions {
+    block_size: u64,
+    cached_blocks: usize,
+}
+
+impl Default for HttpOptions {
+    fn default() -> Self {
+        HttpOptions {
+            block_size: DEFAULT_BLOCK_SIZE,
+            cached_blocks: DEFAULT_CACHED_BLOCKS,
+        }
+    }
+}
+
+impl HttpOptions {
+    /// Creates the default options, which fetch 64KiB blocks and cache up to 64 of them.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the size of each block fetched from the server.
+    pub fn block_size(&mut self, block_size: u64) -> &mut Self {
+        self.block_size = block_size.max(1);
+        self
+    }
+
+    /// Sets how many blocks to keep in the cache, the oldest blocks are discarded first.
+    pub fn cached_blocks(&mut self, cached_blocks: usize) -> &mut Self {
+        self.cached_blocks = cached_blocks.max(1);
+        self
+    }
+}
+
+/// A seekable reader over a file on an HTTP server that supports range requests.
+pub struct HttpReader {
+    agent: Agent,
+    url: String,
+    options: HttpOptions,
+    size: u64,
+    position: u64,
+    blocks: HashMap<u64, Vec<u8>>,
+    /// Cached block numbers in the order they were fetched.
+    fetched: VecDeque<u64>,
+}
+
+impl HttpReader {
+    /// Creates a reader for the given URL using the default options.
+    pub fn new(url: impl Into<String>) -> io::Result<HttpReader> {
+        Self::with_options(url, &HttpOptions::new())
+    }
+
+    /// Creates a reader for the given URL.
+    ///
+    /// The first block is fetched immediately to find the size of the file and to check that
+    /// the server supports range requests.
+    pub fn with_options(url: impl Into<String>, options: &HttpOptions) -> io::Result<HttpReader> {
+        Self::with_agent(Agent::new_with_defaults(), url, options)
+    }
+
+    /// Creates a reader for the given URL that makes requests through `agent`.
+    pub fn with_agent(
+        agent: Agent,
+        url: impl Into<String>,
+        options: &HttpOptions,
+    ) -> io::Result<HttpReader> {
+        let mut reader = HttpReader {
+            agent,
+            url: url.into(),
+            options: options.clone(),
+            size: 0,
+            position: 0,
+            blocks: HashMap::new(),
+            fetched: VecDeque::new(),
+        };
+
+        let (data, size) = reader.fetch_range(0, options.block_size)?;
+        reader.size = size;
+        reader.cache(0, data);
+        Ok(reader)
+    }
+
+    /// Returns the size of the remote file.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Fetches up to `length` bytes from `start`, returning them and the size of the file.
+    fn fetch_range(&self, start: u64, length: u64) -> io::Result<(Vec<u8>, u64)> {
+        let mut response = self
+            .agent
+            .get(&self.url)
+            .header("Range", format!("bytes={}-{}", start, start + length - 1))
+            .call()
+            .map_err(io::Error::other)?;
+
+        if response.status() != 206 {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                format!("{} does not support range requests", self.url),
+            ));
+        }
+
+        let (range_start, size) = response
+            .headers()
+            .get("Content-Range")
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_content_range)
+            .ok_or_else(|| {
+                io::Error::new(ErrorKind::InvalidData, "Missing or invalid Content-Range")
+            })?;
+        if range_start != start {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Requested a range starting at {} but received one starting at {}",
+                    start, range_start
+                ),
+            ));
+        }
+
+        let mut data = Vec::new();
+        response
+            .body_mut()
+            .as_reader()
+            .take(length)
+            .read_to_end(&mut data)?;
+
+        // This is a failed transfer rather than a truncated archive.
+        let expected = length.min(size.saturating_sub(start));
+        if data.len() as u64 != expected {
+            return Err(io::Error::new(
+                ErrorKind::ConnectionAborted,
+                format!(
+                    "Received {} bytes from {} but expected {}",
+                    data.len(),
+                    self.url,
+                    expected
+                ),
+            ));
+        }
+        Ok((data, size))
+    }
+
+    /// Fetches the blocks from `first` to `last` inclusive that are not already cached.
+    fn fetch_blocks(&mut self, first: u64, last: u64) -> io::Result<()> {
+        let block_size = self.options.block_size;
+        let mut block = first;
+        while block <= last {
+            if self.blocks.contains_key(&block) {
+                block += 1;
+                continue;
+            }
+
+            // Fetch a run of missing blocks with a single request.
+            let mut end = block;
+            while end < last && !self.blocks.contains_key(&(end + 1)) {
+                end += 1;
+            }
+            let (data, _) = self.fetch_range(block * block_size, (end - block + 1) * block_size)?;
+            for (offset, chunk) in data.chunks(block_size as usize).enumerate() {
+                self.cache(block + offset as u64, chunk.to_vec());
+            }
+            block = end + 1;
+        }
+        Ok(())
+    }
+
+    /// Adds a block to the cache, discarding the oldest blocks if it is full.
+    fn cache(&mut self, block: u64, data: Vec<u8>) {
+        while self.fetched.len() >= self.options.cached_blocks {
+            if let Some(oldest) = self.fetched.pop_front() {
+                self.blocks.remove(&oldest);
+            }
+        }
+        self.fetched.push_back(block);
+        self.blocks.insert(block, data);
+    }
+}
+
+impl Read for HttpReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.size.saturating_sub(self.position);
+        let length = (buf.len() as u64).min(remaining);
+        if length == 0 {
+            return Ok(0);
+        }
+
+        // Fetch everything needed for this read up front, limited by the size of the cache.
+        let block_size = self.options.block_size;
+        let first = self.position / block_size;
+        let last = ((self.position + length - 1) / block_size)
+            .min(first + self.options.cached_blocks as u64 - 1);
+        self.fetch_blocks(first, last)?;
+
+        let mut count = 0;
+        for block in first..=last {
+            // Fetching a run of blocks may have evicted older ones that were already cached.
+            if !self.blocks.contains_key(&block) {
+                if count > 0 {
+                    break;
+                }
+                self.fetch_blocks(block, block)?;
+            }
+            let data = &self.blocks[&block];
+            let start = (self.position - block * block_size) as usize;
+            let available = &data[start.min(data.len())..];
+            let len = available.len().min(length as usize - count);
+            buf[count..count + len].copy_from_slice(&available[..len]);
+            count += len;
+            self.position += len as u64;
+            if count == length as usize {
+                break;
+            }
+        }
+        Ok(count)
+    }
+}
+
+impl Seek for HttpReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+/// Parses the start of the range and the total size from a `Content-Range` header such as
+/// `bytes 0-99/1234`.
+fn parse_content_range(value: &str) -> Option<(u64, u64)> {
+    let (range, size) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let (start, _) = range.split_once('-')?;
+    Some((start.trim().parse().ok()?, size.trim().parse().ok()?))
+}
+
+impl Mar<HttpReader> {
+    /// Creates a Mar instance that reads a remote file using HTTP range requests.
+    pub fn from_url(url: impl Into<String>) -> Result<Mar<HttpReader>> {
+        Mar::from_buffer(HttpReader::new(url)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+    use crate::testing::{build, read_entry};
+    use crate::write::MarBuilder;
+
+    /// How the test server responds to range requests.
+    #[derive(Clone, Copy)]
+    enum Mode {
+        Ranges,
+        IgnoreRanges,
+        ShortBody,
+        WrongStart,
+    }
+
+    /// A server on a local port that serves `data` at any path.
+    struct Server {
+        url: String,
+        requests: Arc<AtomicUsize>,
+    }
+
+    impl Server {
+        fn new(data: Vec<u8>, mode: Mode) -> Server {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}/test.mar", listener.local_addr().unwrap());
+            let requests = Arc::new(AtomicUsize::new(0));
+
+            let counter = requests.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let Ok(stream) = stream else { break };
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    let _ = respond(stream, &data, mode);
+                }
+            });
+
+            Server { url, requests }
+        }
+
+        fn requests(&self) -> usize {
+            self.requests.load(Ordering::SeqCst)
+        }
+
+        fn reader(&self, options: &HttpOptions) -> io::Result<HttpReader> {
+            // Ignore any proxy configured in the environment.
+            let agent = Agent::new_with_config(Agent::config_builder().proxy(None).build());
+            HttpReader::with_agent(agent, self.url.clone(), options)
+        }
+    }
+
+    fn respond(stream: TcpStream, data: &[u8], mode: Mode) -> io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut range = None;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            let line = line.trim_end().to_ascii_lowercase();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((start, end)) = line
+                .strip_prefix("range: bytes=")
+                .and_then(|value| value.split_once('-'))
+            {
+                range = Some((
+                    start.parse::<usize>().unwrap(),
+                    end.parse::<usize>().unwrap(),
+                ));
+            }
+        }
+
+        let mut stream = stream;
+        let (status, content_range, body) = match (mode, range) {
+            (Mode::IgnoreRanges, _) | (_, None) => ("200 OK", None, data),
+            (_, Some((start, end))) => {
+                let end = (end + 1).min(data.len());
+                let shown_start = match mode {
+                    Mode::WrongStart => start + 1,
+                    _ => start,
+                };
+                let content_range = format!("bytes {}-{}/{}", shown_start, end - 1, data.len());
+                let body = match mode {
+                    Mode::ShortBody => &data[start..start + (end - start) / 2],
+                    _ => &data[start..end],
+                };
+                ("206 Partial Content", Some(content_range), body)
+            }
+        };
+
+        write!(stream, "HTTP/1.1 {}\r\n", status)?;
+        write!(stream, "Content-Length: {}\r\n", body.len())?;
+        if let Some(content_range) = content_range {
+            write!(stream, "Content-Range: {}\r\n", content_range)?;
+        }
+        write!(stream, "Connection: close\r\n\r\n")?;
+        stream.write_all(body)?;
+        stream.flush()
+    }
+
+    fn data() -> Vec<u8> {
+        (0..1000).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn read_at(reader: &mut HttpReader, position: u64, length: usize) -> Vec<u8> {
+        reader.seek(SeekFrom::Start(position)).unwrap();
+        let mut buf = vec![0; length];
+        reader.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn caches_and_evicts_blocks() {
+        let data = data();
+        let server = Server::new(data.clone(), Mode::Ranges);
+        let mut reader = server
+            .reader(HttpOptions::new().block_size(100).cached_blocks(2))
+            .unwrap();
+        assert_eq!(reader.size(), 1000);
+        assert_eq!(server.requests(), 1);
+
+        assert_eq!(read_at(&mut reader, 10, 50), &data[10..60]);
+        assert_eq!(server.requests(), 1);
+        assert_eq!(read_at(&mut reader, 150, 10), &data[150..160]);
+        assert_eq!(server.requests(), 2);
+        assert_eq!(read_at(&mut reader, 120, 10), &data[120..130]);
+        assert_eq!(server.requests(), 2);
+
+        // The third block evicts the first.
+        assert_eq!(read_at(&mut reader, 250, 10), &data[250..260]);
+        assert_eq!(server.requests(), 3);
+        assert_eq!(read_at(&mut reader, 0, 10), &data[0..10]);
+        assert_eq!(server.requests(), 4);
+    }
+
+    #[test]
+    fn reads_across_blocks() {
+        let data = data();
+        let server = Server::new(data.clone(), Mode::Ranges);
+        let mut reader = server.reader(HttpOptions::new().block_size(100)).unwrap();
+
+        // The missing blocks are fetched with a single request.
+        assert_eq!(read_at(&mut reader, 50, 300), &data[50..350]);
+        assert_eq!(server.requests(), 2);
+
+        let mut all = Vec::new();
+        reader.rewind().unwrap();
+        reader.read_to_end(&mut all).unwrap();
+        assert_eq!(all, data);
+    }
+
+    #[test]
+    fn reads_a_mar() {
+        let mut builder = MarBuilder::new();
+        builder.add_entry("a.txt", 0o644, &b"hello"[..]);
+
+        let server = Server::new(build(builder), Mode::Ranges);
+        let mut mar = Mar::from_buffer(server.reader(&HttpOptions::new()).unwrap()).unwrap();
+        assert_eq!(read_entry(&mut mar, "a.txt"), b"hello");
+    }
+
+    #[test]
+    fn rejects_servers_without_ranges() {
+        let server = Server::new(data(), Mode::IgnoreRanges);
+        let error = server.reader(&HttpOptions::new()).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn rejects_short_bodies() {
+        let server = Server::new(data(), Mode::ShortBody);
+        let error = server
+            .reader(HttpOptions::new().block_size(100))
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), ErrorKind::ConnectionAborted);
+    }
+
+    #[test]
+    fn rejects_wrong_ranges() {
+        let server = Server::new(data(), Mode::WrongStart);
+        let error = server
+            .reader(HttpOptions::new().block_size(100))
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+}
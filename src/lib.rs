@@ -13,35 +13,98 @@
 #![warn(missing_docs)]
 
 use std::{
+    collections::HashMap,
     fs::File,
-    io::{self, BufReader, Cursor, ErrorKind, Read, Seek, SeekFrom},
+    io::{BufReader, Read, Seek, SeekFrom, Take, Write},
     path::Path,
 };
 
-use byteorder::{BigEndian, ReadBytesExt};
+pub use error::{MarError, Result};
+
 use compression::CompressedRead;
-use read::{get_info, read_next_item};
+use manifest::{Manifest, MANIFEST_NAMES};
+use read::{
+    additional_blocks_with_info, get_info, read_index_with_options, read_product_info_with_info,
+    read_signatures_with_info, AdditionalBlocks, ReadOptions,
+};
+use signing::{PrivateKey, PublicKey, Signature, SignatureAlgorithm};
 
+pub mod apply;
+#[cfg(feature = "tokio")]
+pub mod asynchronous;
 pub mod compression;
+pub mod error;
 pub mod extract;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod manifest;
+pub mod patch;
 pub mod read;
+pub mod shared;
+pub mod signing;
+pub mod slice;
+pub mod stream;
+#[cfg(test)]
+mod testing;
+pub mod update;
+mod validate;
+pub mod write;
 
 /// Metadata about an entire MAR file.
+#[derive(Clone, Debug)]
 pub struct MarFileInfo {
-    offset_to_index: u32,
-    #[allow(dead_code)]
-    has_signature_block: bool,
-    #[allow(dead_code)]
-    num_signatures: u32,
-    #[allow(dead_code)]
-    has_additional_blocks: bool,
-    #[allow(dead_code)]
-    offset_additional_blocks: u32,
-    #[allow(dead_code)]
-    num_additional_blocks: u32,
+    /// Position of the index within the archive file.
+    pub offset_to_index: u32,
+    /// Whether the file has a signature block, old-style MAR files do not.
+    pub has_signature_block: bool,
+    /// Number of signatures in the signature block.
+    pub num_signatures: u32,
+    /// Whether the file has any additional blocks after the signature block.
+    pub has_additional_blocks: bool,
+    /// Position of the first additional block within the archive file.
+    pub offset_additional_blocks: u32,
+    /// Number of additional blocks.
+    pub num_additional_blocks: u32,
+}
+
+/// Information about the product that a MAR file is intended to update.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProductInformation {
+    /// The MAR channel ID that the update is served on.
+    pub mar_channel_id: String,
+    /// The version of the product that the update contains.
+    pub product_version: String,
+}
+
+/// A block of extra data stored between the signature block and the content of a MAR file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AdditionalBlock {
+    /// Information about the product that the MAR file updates.
+    ProductInformation(ProductInformation),
+    /// A block of a type that this crate does not understand.
+    ///
+    /// The identifier of the product information block cannot be used, archives with such a
+    /// block are rejected when written.
+    Unknown {
+        /// The identifier of the block type.
+        id: u32,
+        /// The content of the block, excluding its size and identifier.
+        data: Vec<u8>,
+    },
+}
+
+impl AdditionalBlock {
+    /// Returns the identifier of the block type.
+    pub fn id(&self) -> u32 {
+        match self {
+            AdditionalBlock::ProductInformation(_) => write::PRODUCT_INFO_BLOCK_ID,
+            AdditionalBlock::Unknown { id, .. } => *id,
+        }
+    }
 }
 
 /// An entry in the MAR index.
+#[derive(Clone, Debug)]
 pub struct MarItem {
     /// Position of the item within the archive file.
     offset: u32,
@@ -50,33 +113,150 @@ pub struct MarItem {
     /// File mode bits.
     pub flags: u32,
     /// File path.
+    ///
+    /// If the name is not valid UTF-8 this is a lossy conversion of it, suitable for display.
     pub name: String,
+    /// The raw bytes of the name, if it is not valid UTF-8.
+    raw_name: Option<Vec<u8>>,
+}
+
+impl MarItem {
+    /// Returns the name of the entry exactly as it is stored in the index.
+    pub fn name_bytes(&self) -> &[u8] {
+        match &self.raw_name {
+            Some(bytes) => bytes,
+            None => self.name.as_bytes(),
+        }
+    }
+
+    /// Returns true if the stored name is valid UTF-8, and so `name` is exact.
+    pub fn is_utf8_name(&self) -> bool {
+        self.raw_name.is_none()
+    }
 }
 
 /// A high level interface to read the contents of a mar file.
 pub struct Mar<R> {
     info: MarFileInfo,
+    index: Index,
     buffer: R,
 }
 
+/// The parsed index of a mar file.
+pub(crate) struct Index {
+    /// The entries in the order they appear in the index.
+    items: Vec<MarItem>,
+    /// Maps each stored name to the position of its first entry in `items`. Names that are not
+    /// UTF-8 are keyed by their raw bytes so they cannot collide with the lossy form of another.
+    by_name: HashMap<Vec<u8>, usize>,
+    /// Positions in `items` ordered by name.
+    sorted: Vec<usize>,
+}
+
+impl Index {
+    pub(crate) fn new(items: Vec<MarItem>) -> Index {
+        let mut by_name = HashMap::with_capacity(items.len());
+        for (position, item) in items.iter().enumerate() {
+            by_name
+                .entry(item.name_bytes().to_vec())
+                .or_insert(position);
+        }
+
+        let mut sorted: Vec<usize> = (0..items.len()).collect();
+        sorted.sort_by(|a, b| items[*a].name_bytes().cmp(items[*b].name_bytes()));
+
+        Index {
+            items,
+            by_name,
+            sorted,
+        }
+    }
+
+    /// Returns the entries in the order they appear in the index.
+    pub(crate) fn items(&self) -> &[MarItem] {
+        &self.items
+    }
+
+    /// Returns the first entry with the given name.
+    pub(crate) fn get(&self, name: &str) -> Option<&MarItem> {
+        self.by_name
+            .get(name.as_bytes())
+            .map(|position| &self.items[*position])
+    }
+
+    /// Returns true if there is an entry with the given name.
+    pub(crate) fn contains(&self, name: &str) -> bool {
+        self.by_name.contains_key(name.as_bytes())
+    }
+
+    /// Returns the entries ordered by name.
+    pub(crate) fn sorted(&self) -> impl Iterator<Item = &MarItem> {
+        self.sorted.iter().map(|position| &self.items[*position])
+    }
+}
+
 impl<R> Mar<R>
 where
     R: Read + Seek,
 {
     /// Creates a Mar instance from any seekable readable.
-    pub fn from_buffer(mut buffer: R) -> io::Result<Mar<R>> {
+    ///
+    /// The index is read and parsed up front so looking up entries needs no further reads.
+    pub fn from_buffer(buffer: R) -> Result<Mar<R>> {
+        Self::from_buffer_with_options(buffer, &ReadOptions::new())
+    }
+
+    /// Creates a Mar instance from any seekable readable.
+    ///
+    /// Behaves as `from_buffer` with the given options.
+    pub fn from_buffer_with_options(mut buffer: R, options: &ReadOptions) -> Result<Mar<R>> {
         let info = get_info(&mut buffer)?;
+        let index = Index::new(read_index_with_options(&mut buffer, options)?);
+
+        Ok(Mar {
+            info,
+            index,
+            buffer,
+        })
+    }
 
-        Ok(Mar { info, buffer })
+    /// Creates a Mar instance from any seekable readable, rejecting it if `validate` finds any
+    /// problems.
+    ///
+    /// The first problem found is returned.
+    pub fn from_buffer_strict(buffer: R) -> Result<Mar<R>> {
+        let mut mar = Self::from_buffer(buffer)?;
+        match mar.validate()?.into_iter().next() {
+            Some(violation) => Err(violation),
+            None => Ok(mar),
+        }
     }
 }
 
 impl Mar<BufReader<File>> {
     /// Creates a Mar instance from a local file path.
-    pub fn from_path<P: AsRef<Path>>(path: P) -> io::Result<Mar<BufReader<File>>> {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Mar<BufReader<File>>> {
         let buffer = BufReader::new(File::open(path)?);
         Self::from_buffer(buffer)
     }
+
+    /// Creates a Mar instance from a local file path.
+    ///
+    /// Behaves as `from_path` with the given options.
+    pub fn from_path_with_options<P: AsRef<Path>>(
+        path: P,
+        options: &ReadOptions,
+    ) -> Result<Mar<BufReader<File>>> {
+        let buffer = BufReader::new(File::open(path)?);
+        Self::from_buffer_with_options(buffer, options)
+    }
+
+    /// Creates a Mar instance from a local file path, rejecting it if `validate` finds any
+    /// problems.
+    pub fn from_path_strict<P: AsRef<Path>>(path: P) -> Result<Mar<BufReader<File>>> {
+        let buffer = BufReader::new(File::open(path)?);
+        Self::from_buffer_strict(buffer)
+    }
 }
 
 impl<R> Mar<R>
@@ -84,45 +264,170 @@ where
     R: Read + Seek,
 {
     /// Reads the contents of a file from this mar.
-    pub fn read<'a>(&'a mut self, item: &MarItem) -> io::Result<CompressedRead<'a, R>> {
+    pub fn read<'a>(&'a mut self, item: &MarItem) -> Result<CompressedRead<'a, R>> {
+        self.check_span(item)?;
         self.buffer.seek(SeekFrom::Start(item.offset as u64))?;
         CompressedRead::new(&mut self.buffer, item.length as u64)
     }
 
-    /// Returns an Iterator to the list of files in this mar.
-    pub fn files(&mut self) -> io::Result<Files> {
-        self.buffer
-            .seek(SeekFrom::Start(self.info.offset_to_index as u64))?;
+    /// Reads the stored bytes of a file from this mar without decompressing them.
+    pub fn read_raw(&mut self, item: &MarItem) -> Result<Take<&mut R>> {
+        self.check_span(item)?;
+        self.buffer.seek(SeekFrom::Start(item.offset as u64))?;
+        Ok(self.buffer.by_ref().take(item.length as u64))
+    }
 
-        // Read the index into memory.
-        let size_of_index = self.buffer.read_u32::<BigEndian>()?;
-        let mut index = vec![0; size_of_index as usize];
-        self.buffer.read_exact(&mut index)?;
+    /// Reads the contents of the named file from this mar, if it exists.
+    pub fn read_by_name(&mut self, name: &str) -> Result<Option<CompressedRead<'_, R>>> {
+        match self.entry(name).cloned() {
+            Some(item) => Ok(Some(self.read(&item)?)),
+            None => Ok(None),
+        }
+    }
 
-        Ok(Files {
-            index: Cursor::new(index),
-        })
+    /// Calls `f` with each entry and a reader for its decompressed contents.
+    ///
+    /// Entries are visited in the order their data is stored so the underlying reader only moves
+    /// forwards.
+    pub fn for_each_entry<F>(&mut self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&MarItem, &mut CompressedRead<'_, R>) -> Result<()>,
+    {
+        let mut items: Vec<&MarItem> = self.index.items().iter().collect();
+        items.sort_by_key(|item| item.offset);
+        for item in items {
+            read::check_span(&self.info, item)?;
+            self.buffer.seek(SeekFrom::Start(item.offset as u64))?;
+            f(
+                item,
+                &mut CompressedRead::new(&mut self.buffer, item.length as u64)?,
+            )?;
+        }
+        Ok(())
     }
-}
 
-/// An iterator over the files in a mar.
-pub struct Files {
-    index: Cursor<Vec<u8>>,
-}
+    /// Checks that the stored data of an entry lies within the content of this mar.
+    pub(crate) fn check_span(&self, item: &MarItem) -> Result<()> {
+        read::check_span(&self.info, item)
+    }
+
+    /// Reads the update manifest from this mar, if it has one.
+    ///
+    /// `updatev3.manifest` is preferred over `updatev2.manifest`.
+    pub fn manifest(&mut self) -> Result<Option<Manifest>> {
+        let name = MANIFEST_NAMES.iter().find(|name| self.contains(name));
 
-impl Iterator for Files {
-    type Item = io::Result<MarItem>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        match read_next_item(&mut self.index) {
-            Ok(item) => Some(Ok(item)),
-            Err(e) => {
-                if e.kind() == ErrorKind::UnexpectedEof {
-                    None
-                } else {
-                    Some(Err(e))
+        match name {
+            Some(name) => {
+                let mut text = String::new();
+                if let Some(mut reader) = self.read_by_name(name)? {
+                    reader.read_to_string(&mut text)?;
                 }
+                Ok(Some(Manifest::parse(&text)?))
             }
+            None => Ok(None),
         }
     }
+
+    /// Checks the structure of this mar as strictly as Firefox does, returning every problem
+    /// found.
+    ///
+    /// The index and every entry must lie within the file, entries must not overlap the header,
+    /// the signature block, the index or each other, and names must be non-empty UTF-8 of a
+    /// reasonable length. An empty list means the mar is valid.
+    pub fn validate(&mut self) -> Result<Vec<MarError>> {
+        validate::validate(&mut self.buffer, &self.info)
+    }
+
+    /// Returns the metadata about this mar.
+    pub fn info(&self) -> &MarFileInfo {
+        &self.info
+    }
+
+    /// Returns the product information from this mar, if it has any.
+    pub fn product_info(&mut self) -> Result<Option<ProductInformation>> {
+        read_product_info_with_info(&mut self.buffer, &self.info)
+    }
+
+    /// Returns an Iterator over the additional blocks in this mar.
+    pub fn additional_blocks(&mut self) -> Result<AdditionalBlocks<&mut R>> {
+        Ok(additional_blocks_with_info(&mut self.buffer, &self.info))
+    }
+
+    /// Returns the signatures in this mar.
+    pub fn signatures(&mut self) -> Result<Vec<Signature>> {
+        read_signatures_with_info(&mut self.buffer, &self.info)
+    }
+
+    /// Verifies that every one of the given keys matches a signature in this mar.
+    pub fn verify(&mut self, keys: &[PublicKey]) -> Result<()> {
+        signing::verify_with_info(&mut self.buffer, &self.info, keys)
+    }
+
+    /// Writes a copy of this mar signed with the given keys to `output`.
+    pub fn sign<W>(&mut self, output: W, keys: &[(PrivateKey, SignatureAlgorithm)]) -> Result<()>
+    where
+        W: Write + Seek,
+    {
+        signing::sign(&mut self.buffer, output, keys)
+    }
+}
+
+impl<R> Mar<R> {
+    /// Returns the entries in this mar, in the order they appear in the index.
+    pub fn files(&self) -> &[MarItem] {
+        self.index.items()
+    }
+
+    /// Returns the entry with the given name, if there is one.
+    ///
+    /// If more than one entry has the name the first in the index is returned. Entries whose
+    /// names are not UTF-8 never match, even by their lossy name.
+    pub fn entry(&self, name: &str) -> Option<&MarItem> {
+        self.index.get(name)
+    }
+
+    /// Returns true if this mar has an entry with the given name.
+    pub fn contains(&self, name: &str) -> bool {
+        self.index.contains(name)
+    }
+
+    /// Returns the number of entries in this mar.
+    pub fn len(&self) -> usize {
+        self.index.items().len()
+    }
+
+    /// Returns true if this mar has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.index.items().is_empty()
+    }
+
+    /// Returns an Iterator over the entries in this mar, ordered by name.
+    pub fn sorted_entries(&self) -> impl Iterator<Item = &MarItem> {
+        self.index.sorted()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::testing::{lossy_collision, read_entry};
+
+    #[test]
+    fn lookup_ignores_lossy_names() {
+        let options = ReadOptions::new().non_utf8_names(true).clone();
+        let mut mar =
+            Mar::from_buffer_with_options(Cursor::new(lossy_collision()), &options).unwrap();
+
+        let raw = &mar.files()[0];
+        assert_eq!(raw.name, "a\u{fffd}.txt");
+        assert_eq!(raw.name_bytes(), b"a\xe2\x82.txt");
+        assert!(!raw.is_utf8_name());
+
+        let item = mar.entry("a\u{fffd}.txt").unwrap();
+        assert!(item.is_utf8_name());
+        assert_eq!(read_entry(&mut mar, "a\u{fffd}.txt"), b"utf-8");
+    }
 }
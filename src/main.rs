@@ -2,19 +2,468 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use std::env::args;
+use std::env::{self, args};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, ErrorKind, Write};
+use std::process::exit;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use mar::extract::{extract_with_options, ExtractOptions};
+use mar::patch::apply_patch;
+use mar::read::ReadOptions;
+use mar::signing::{self, PrivateKey, PublicKey, SignatureAlgorithm};
+use mar::write::{set_product_info, MarBuilder};
+use mar::{AdditionalBlock, Mar, ProductInformation};
+
+const USAGE: &str = "usage:
+Create a MAR file:
+  mar [-H MARChannelID -V ProductVersion] [-C workingDir] -c archive.mar [files...]
+Print information on a MAR file:
+  mar [-C workingDir] -t archive.mar
+Print detailed information on a MAR file including signatures:
+  mar [-C workingDir] -T archive.mar
+Refresh the product information block of a MAR file:
+  mar -H MARChannelID -V ProductVersion [-C workingDir] -i unsigned_archive_to_refresh.mar
+Extract a MAR file:
+  mar [-C workingDir] -x archive.mar
+Sign a MAR file:
+  mar [-C workingDir] [-a sha1|sha384] -k keyFile... -s archive.mar out_signed_archive.mar
+Strip a MAR signature:
+  mar [-C workingDir] -r signed_input_archive.mar output_archive.mar
+Extract a MAR signature:
+  mar [-C workingDir] -n(i) -X signed_input_archive.mar base_64_encoded_signature_file
+Import a MAR signature:
+  mar [-C workingDir] -n(i) -I signed_input_archive.mar base_64_encoded_signature_file changed_signed_output.mar
+(i) is the index of the certificate to extract
+Verify a MAR file:
+  mar [-C workingDir] -D DERFilePath... -v signed_archive.mar
+Apply an MBDIFF10 patch to a file:
+  mar patch source_file patch_file destination_file
+At most 8 signature certificates can be specified";
 
 fn main() {
-    let mut args = args().skip(1);
+    if let Err(e) = run(args().skip(1).collect()) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+/// Options that may precede the command.
+struct Options {
+    channel_id: Option<String>,
+    product_version: Option<String>,
+    public_keys: Vec<String>,
+    private_keys: Vec<(PrivateKey, SignatureAlgorithm)>,
+    signature_index: usize,
+}
+
+fn run(args: Vec<String>) -> io::Result<()> {
+    let mut args = args.into_iter().peekable();
+    let mut options = Options {
+        channel_id: None,
+        product_version: None,
+        public_keys: Vec::new(),
+        private_keys: Vec::new(),
+        signature_index: 0,
+    };
+    let mut algorithm = SignatureAlgorithm::RsaPkcs1Sha384;
+
+    if args.peek().map(String::as_str) == Some("patch") {
+        args.next();
+        return match args.collect::<Vec<_>>().as_slice() {
+            [source, patch, dest] => {
+                let mut dest = BufWriter::new(File::create(dest)?);
+                apply_patch(File::open(source)?, File::open(patch)?, &mut dest)?;
+                dest.flush()
+            }
+            _ => Err(usage()),
+        };
+    }
+
+    let command = loop {
+        let arg = args.next().ok_or_else(usage)?;
+        match arg.as_str() {
+            "-c" | "-t" | "-T" | "-x" | "-v" | "-s" | "-i" | "-r" | "-X" | "-I" => break arg,
+            "-C" => env::set_current_dir(value(&mut args, "-C")?)?,
+            "-H" => options.channel_id = Some(value(&mut args, "-H")?),
+            "-V" => options.product_version = Some(value(&mut args, "-V")?),
+            "-a" => algorithm = parse_algorithm(value(&mut args, "-a")?)?,
+            "-k" => {
+                let key = PrivateKey::from_path(value(&mut args, "-k")?)?;
+                options.private_keys.push((key, algorithm));
+            }
+            // -D also accepts an index, e.g. -D0, for symmetry with -n.
+            d if d
+                .strip_prefix("-D")
+                .is_some_and(|index| index.bytes().all(|b| b.is_ascii_digit())) =>
+            {
+                options.public_keys.push(value(&mut args, "-D")?)
+            }
+            n if n.starts_with("-n") => {
+                if let Ok(index) = n[2..].parse() {
+                    options.signature_index = index;
+                }
+                if args.peek().is_some_and(|next| !next.starts_with('-')) {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidInput,
+                        "NSS certificate names are not supported, use -k or -D with a key file",
+                    ));
+                }
+            }
+            _ => return Err(usage()),
+        }
+    };
+
+    let rest: Vec<String> = args.collect();
+    match (command.as_str(), rest.as_slice()) {
+        ("-c", [archive, files @ ..]) => create(&options, archive, files),
+        ("-t", [archive]) => list(archive, false, &mut io::stdout().lock()),
+        ("-T", [archive]) => list(archive, true, &mut io::stdout().lock()),
+        ("-x", [archive]) => Ok(extract_with_options(
+            archive,
+            ".",
+            ExtractOptions::new().non_utf8_names(true),
+        )?),
+        ("-i", [archive]) => refresh_product_info(&options, archive),
+        ("-v", [archive]) => verify(&options, archive),
+        ("-s", paths) => sign(&options, paths),
+        ("-r", [input, output]) => {
+            let mut output = BufWriter::new(File::create(output)?);
+            Ok(signing::strip_signatures(File::open(input)?, &mut output)?)
+        }
+        ("-X", [archive, signature_file]) => export_signature(&options, archive, signature_file),
+        ("-I", [input, signature_file, output]) => {
+            let signature = BASE64
+                .decode(
+                    fs::read_to_string(signature_file)?
+                        .split_whitespace()
+                        .collect::<String>(),
+                )
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            let mut output = BufWriter::new(File::create(output)?);
+            Ok(signing::import_signature(
+                File::open(input)?,
+                &mut output,
+                options.signature_index,
+                &signature,
+            )?)
+        }
+        _ => Err(usage()),
+    }
+}
+
+fn usage() -> io::Error {
+    io::Error::new(ErrorKind::InvalidInput, USAGE)
+}
+
+/// Takes the value of an option from the arguments.
+fn value<I: Iterator<Item = String>>(args: &mut I, option: &str) -> io::Result<String> {
+    args.next().ok_or_else(|| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} requires a value", option),
+        )
+    })
+}
+
+fn parse_algorithm(name: String) -> io::Result<SignatureAlgorithm> {
+    match name.as_str() {
+        "sha1" => Ok(SignatureAlgorithm::RsaPkcs1Sha1),
+        "sha384" => Ok(SignatureAlgorithm::RsaPkcs1Sha384),
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "-a requires an algorithm of sha1 or sha384",
+        )),
+    }
+}
+
+/// Returns the product information if both the channel ID and product version were given.
+fn product_info(options: &Options) -> io::Result<Option<ProductInformation>> {
+    match (&options.channel_id, &options.product_version) {
+        (Some(mar_channel_id), Some(product_version)) => Ok(Some(ProductInformation {
+            mar_channel_id: mar_channel_id.clone(),
+            product_version: product_version.clone(),
+        })),
+        (None, None) => Ok(None),
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "-H and -V must be used together",
+        )),
+    }
+}
+
+fn create(options: &Options, archive: &str, files: &[String]) -> io::Result<()> {
+    let mut builder = MarBuilder::new();
+    if let Some(info) = product_info(options)? {
+        builder.product_information(info);
+    }
+    for file in files {
+        builder.add_file(file.as_str(), file);
+    }
+
+    Ok(builder.build(BufWriter::new(File::create(archive)?))?)
+}
+
+fn list<W: Write>(archive: &str, detailed: bool, out: &mut W) -> io::Result<()> {
+    let mut mar = Mar::from_path_with_options(archive, ReadOptions::new().non_utf8_names(true))?;
+
+    if detailed {
+        let info = mar.info().clone();
+        if info.has_signature_block {
+            writeln!(
+                out,
+                "Signature block found with {} signature{}",
+                info.num_signatures,
+                if info.num_signatures != 1 { "s" } else { "" }
+            )?;
+            for (i, signature) in mar.signatures()?.iter().enumerate() {
+                writeln!(
+                    out,
+                    "  - Signature {}: algorithm {}, {} bytes",
+                    i,
+                    signature.algorithm_id,
+                    signature.data.len()
+                )?;
+            }
+        }
+        if info.has_additional_blocks {
+            writeln!(
+                out,
+                "{} additional block{} found:",
+                info.num_additional_blocks,
+                if info.num_additional_blocks != 1 {
+                    "s"
+                } else {
+                    ""
+                }
+            )?;
+            for block in mar.additional_blocks()? {
+                match block? {
+                    AdditionalBlock::ProductInformation(info) => {
+                        writeln!(out, "  - Product Information Block:")?;
+                        writeln!(out, "    - MAR channel name: {}", info.mar_channel_id)?;
+                        writeln!(out, "    - Product version: {}", info.product_version)?;
+                    }
+                    AdditionalBlock::Unknown { id, data } => {
+                        writeln!(out, "  - Unknown Block {}: {} bytes", id, data.len())?;
+                    }
+                }
+            }
+        }
+        writeln!(out)?;
+    }
+
+    writeln!(out, "SIZE\tMODE\tNAME")?;
+    for item in mar.files() {
+        writeln!(out, "{}\t0{:o}\t{}", item.length, item.flags, item.name)?;
+    }
+    Ok(())
+}
+
+fn refresh_product_info(options: &Options, archive: &str) -> io::Result<()> {
+    let info = product_info(options)?
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "-i requires both -H and -V"))?;
+    let file = OpenOptions::new().read(true).write(true).open(archive)?;
+    Ok(set_product_info(file, &info)?)
+}
+
+fn verify(options: &Options, archive: &str) -> io::Result<()> {
+    if options.public_keys.is_empty() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "-v requires at least one -D key file",
+        ));
+    }
+    let keys = options
+        .public_keys
+        .iter()
+        .map(PublicKey::from_path)
+        .collect::<mar::Result<Vec<_>>>()?;
 
-    match args.next().as_deref() {
-        Some("-X") => {
-            let path = args.next().expect("-X requires a file name");
-            mar::extract::extract(path).unwrap();
+    Ok(Mar::from_path(archive)?.verify(&keys)?)
+}
+
+fn sign(options: &Options, paths: &[String]) -> io::Result<()> {
+    match paths {
+        [input, output] if !options.private_keys.is_empty() => {
+            let mut mar = Mar::from_path(input)?;
+            let output = BufWriter::new(File::create(output)?);
+            Ok(mar.sign(output, &options.private_keys)?)
         }
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "Usage: mar [-a sha1|sha384] -k key.pem... -s input.mar output.mar",
+        )),
+    }
+}
+
+fn export_signature(options: &Options, archive: &str, signature_file: &str) -> io::Result<()> {
+    let signatures = Mar::from_path(archive)?.signatures()?;
+    let signature = signatures.get(options.signature_index).ok_or_else(|| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "MAR file has no signature at index {}",
+                options.signature_index
+            ),
+        )
+    })?;
+
+    fs::write(signature_file, BASE64.encode(&signature.data))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::Mutex;
+
+    use rsa::pkcs8::{EncodePrivateKey, EncodePublicKey, LineEnding};
+    use rsa::rand_core::OsRng;
+    use rsa::RsaPrivateKey;
+
+    use super::*;
+
+    /// Held by tests that change the working directory with -C.
+    static WORKING_DIR: Mutex<()> = Mutex::new(());
+
+    fn mar(args: &[&str]) -> io::Result<()> {
+        run(args.iter().map(|arg| arg.to_string()).collect())
+    }
+
+    fn path(dir: &Path, name: &str) -> String {
+        dir.join(name).to_str().unwrap().to_owned()
+    }
+
+    fn listing(archive: &str) -> String {
+        let mut out = Vec::new();
+        list(archive, true, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    /// Writes a new key pair to `dir`, returning the paths of the private and public keys.
+    fn write_key(dir: &Path, name: &str) -> (String, String) {
+        let key = RsaPrivateKey::new(&mut OsRng, 1024).unwrap();
+        let private = path(dir, &format!("{}.pem", name));
+        fs::write(&private, key.to_pkcs8_pem(LineEnding::LF).unwrap()).unwrap();
+        let public = path(dir, &format!("{}.der", name));
+        let der = key.to_public_key().to_public_key_der().unwrap();
+        fs::write(&public, der.as_bytes()).unwrap();
+        (private, public)
+    }
 
-        Some(x) => eprintln!("Unrecognized option {:?}", x),
+    #[test]
+    fn create_list_and_extract() {
+        let _lock = WORKING_DIR.lock().unwrap_or_else(|e| e.into_inner());
+        let working_dir = env::current_dir().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        fs::write(dir.join("a.txt"), "hello").unwrap();
+        fs::create_dir(dir.join("b")).unwrap();
+        fs::write(dir.join("b/c.txt"), "world").unwrap();
+
+        let result = (|| {
+            let root = dir.to_str().unwrap();
+            let archive = path(dir, "test.mar");
+            mar(&[
+                "-C", root, "-H", "release", "-V", "100.0", "-c", "test.mar", "a.txt", "b/c.txt",
+            ])?;
+            let text = listing(&archive);
+            assert!(
+                text.contains("    - MAR channel name: release\n"),
+                "{}",
+                text
+            );
+            assert!(text.contains("    - Product version: 100.0\n"), "{}", text);
+            assert!(
+                text.ends_with("NAME\n5\t0644\ta.txt\n5\t0644\tb/c.txt\n"),
+                "{}",
+                text
+            );
+
+            mar(&["-H", "beta", "-V", "101.0", "-i", &archive])?;
+            assert!(listing(&archive).contains("    - Product version: 101.0\n"));
+
+            let output = path(dir, "output");
+            fs::create_dir(&output).unwrap();
+            mar(&["-C", &output, "-x", &archive])?;
+            assert_eq!(fs::read_to_string(path(dir, "output/a.txt"))?, "hello");
+            assert_eq!(fs::read_to_string(path(dir, "output/b/c.txt"))?, "world");
+            Ok::<_, io::Error>(())
+        })();
+
+        env::set_current_dir(working_dir).unwrap();
+        result.unwrap();
+    }
 
-        _ => eprintln!("Usage: [TODO]"),
+    #[test]
+    fn sign_verify_and_move_signatures() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let (first, first_public) = write_key(dir, "first");
+        let (second, second_public) = write_key(dir, "second");
+
+        let input = path(dir, "input.mar");
+        let mut builder = MarBuilder::new();
+        builder.add_entry("a.txt", 0o644, &b"hello"[..]);
+        builder.build(File::create(&input).unwrap()).unwrap();
+
+        let signed = path(dir, "signed.mar");
+        mar(&[
+            "-a", "sha1", "-k", &first, "-a", "sha384", "-k", &second, "-s", &input, &signed,
+        ])
+        .unwrap();
+        mar(&["-D", &first_public, "-D1", &second_public, "-v", &signed]).unwrap();
+        let text = listing(&signed);
+        let expected = "Signature block found with 2 signatures\n\
+                        \x20 - Signature 0: algorithm 1, 128 bytes\n\
+                        \x20 - Signature 1: algorithm 2, 128 bytes\n";
+        assert!(text.starts_with(expected), "{}", text);
+
+        // Move a signature made with one key into an archive signed with a placeholder key.
+        let placeholder = path(dir, "placeholder.mar");
+        let real = path(dir, "real.mar");
+        let signature = path(dir, "signature.b64");
+        let imported = path(dir, "imported.mar");
+        mar(&["-k", &first, "-s", &input, &placeholder]).unwrap();
+        mar(&["-k", &second, "-s", &input, &real]).unwrap();
+        mar(&["-n0", "-X", &real, &signature]).unwrap();
+        mar(&["-n0", "-I", &placeholder, &signature, &imported]).unwrap();
+        mar(&["-D", &second_public, "-v", &imported]).unwrap();
+        assert!(mar(&["-D", &second_public, "-v", &placeholder]).is_err());
+        assert!(mar(&["-n1", "-X", &real, &signature]).is_err());
+
+        let stripped = path(dir, "stripped.mar");
+        mar(&["-r", &signed, &stripped]).unwrap();
+        assert_eq!(fs::read(&stripped).unwrap(), fs::read(&input).unwrap());
+        assert!(mar(&["-D", &first_public, "-v", &stripped]).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        let usage_error = |args: &[&str]| match mar(args) {
+            Err(e) => e.kind() == ErrorKind::InvalidInput && e.to_string() == USAGE,
+            Ok(()) => false,
+        };
+
+        assert!(usage_error(&[]));
+        assert!(usage_error(&["-q", "a.mar"]));
+        assert!(usage_error(&["-Dfoo", "key.der", "-v", "a.mar"]));
+        assert!(usage_error(&["-D1a", "key.der", "-v", "a.mar"]));
+        assert!(usage_error(&["-t", "a.mar", "b.mar"]));
+        assert!(usage_error(&["patch", "a", "b"]));
+
+        for args in [
+            &["-v", "a.mar"][..],
+            &["-s", "a.mar", "b.mar"],
+            &["-a", "md5", "-s", "a.mar", "b.mar"],
+            &["-H", "release", "-i", "a.mar"],
+            &["-n", "certificate", "-X", "a.mar", "sig"],
+            &["-k"],
+        ] {
+            let error = mar(args).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidInput, "{:?}", args);
+            assert_ne!(error.to_string(), USAGE, "{:?}", args);
+        }
     }
 }
@@ -0,0 +1,456 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Parsing and generating the update manifests found in Firefox update MAR files.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Names of the manifest entries, in order of preference.
+pub const MANIFEST_NAMES: &[&str] = &["updatev3.manifest", "updatev2.manifest"];
+
+/// The type of update described by a manifest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpdateType {
+    /// A complete update that replaces the entire installation.
+    Complete,
+    /// A partial update that patches a specific earlier version.
+    Partial,
+}
+
+impl UpdateType {
+    fn as_str(self) -> &'static str {
+        match self {
+            UpdateType::Complete => "complete",
+            UpdateType::Partial => "partial",
+        }
+    }
+}
+
+/// A single instruction from an update manifest.
+///
+/// Paths are relative to the installation directory, directories end with a `/`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    /// Adds or replaces a file.
+    Add {
+        /// The file to add.
+        path: String,
+    },
+    /// Adds or replaces a file only if the test file exists.
+    AddIf {
+        /// The file or directory whose existence is tested.
+        test: String,
+        /// The file to add.
+        path: String,
+    },
+    /// Adds a file only if the test file does not exist.
+    AddIfNot {
+        /// The file whose existence is tested.
+        test: String,
+        /// The file to add.
+        path: String,
+    },
+    /// Patches an existing file.
+    Patch {
+        /// The entry in the archive containing the patch.
+        patch: String,
+        /// The file to patch.
+        path: String,
+    },
+    /// Patches an existing file only if the test file exists.
+    PatchIf {
+        /// The file or directory whose existence is tested.
+        test: String,
+        /// The entry in the archive containing the patch.
+        patch: String,
+        /// The file to patch.
+        path: String,
+    },
+    /// Removes a file.
+    Remove {
+        /// The file to remove.
+        path: String,
+    },
+    /// Removes a directory if it is empty.
+    RemoveDir {
+        /// The directory to remove.
+        path: String,
+    },
+    /// Removes a directory and everything in it.
+    RemoveDirRecursive {
+        /// The directory to remove.
+        path: String,
+    },
+}
+
+impl Instruction {
+    /// Returns the arguments of the instruction in the order they are written.
+    fn arguments(&self) -> Vec<&str> {
+        match self {
+            Instruction::Add { path }
+            | Instruction::Remove { path }
+            | Instruction::RemoveDir { path }
+            | Instruction::RemoveDirRecursive { path } => vec![path],
+            Instruction::AddIf { test, path } | Instruction::AddIfNot { test, path } => {
+                vec![test, path]
+            }
+            Instruction::Patch { patch, path } => vec![patch, path],
+            Instruction::PatchIf { test, patch, path } => vec![test, patch, path],
+        }
+    }
+
+    /// Checks that the instruction can be written to a manifest and parsed again.
+    ///
+    /// Manifests have no way to escape characters, so arguments cannot be empty or contain
+    /// quotes or line breaks.
+    pub fn validate(&self) -> Result<(), ManifestError> {
+        self.validate_line(0)
+    }
+
+    fn validate_line(&self, line: usize) -> Result<(), ManifestError> {
+        for argument in self.arguments() {
+            if argument.is_empty() {
+                return Err(ManifestError::new(line, "Empty argument"));
+            }
+            if argument.contains(['"', '\n', '\r']) {
+                return Err(ManifestError::new(
+                    line,
+                    format!("Argument {:?} cannot be written to a manifest", argument),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Instruction::Add { path } => write!(f, "add \"{}\"", path),
+            Instruction::AddIf { test, path } => write!(f, "add-if \"{}\" \"{}\"", test, path),
+            Instruction::AddIfNot { test, path } => {
+                write!(f, "add-if-not \"{}\" \"{}\"", test, path)
+            }
+            Instruction::Patch { patch, path } => write!(f, "patch \"{}\" \"{}\"", patch, path),
+            Instruction::PatchIf { test, patch, path } => {
+                write!(f, "patch-if \"{}\" \"{}\" \"{}\"", test, patch, path)
+            }
+            Instruction::Remove { path } => write!(f, "remove \"{}\"", path),
+            Instruction::RemoveDir { path } => write!(f, "rmdir \"{}\"", path),
+            Instruction::RemoveDirRecursive { path } => write!(f, "rmrfdir \"{}\"", path),
+        }
+    }
+}
+
+/// An update manifest.
+///
+/// The `Display` implementation produces the manifest text, use [`Manifest::validate`] first to
+/// check that it can be parsed again.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Manifest {
+    /// The type of update.
+    pub update_type: UpdateType,
+    /// The instructions to perform, in order.
+    pub instructions: Vec<Instruction>,
+}
+
+impl Manifest {
+    /// Parses the text of a manifest.
+    pub fn parse(text: &str) -> Result<Manifest, ManifestError> {
+        let mut update_type = None;
+        let mut instructions = Vec::new();
+
+        for (line, tokens) in lines(text) {
+            let tokens = tokens?;
+            if tokens[0] == "type" {
+                if update_type.is_some() {
+                    return Err(ManifestError::new(line, "Duplicate type instruction"));
+                }
+                update_type = Some(match args::<1>(line, &tokens)? {
+                    ["complete"] => UpdateType::Complete,
+                    ["partial"] => UpdateType::Partial,
+                    [other] => {
+                        return Err(ManifestError::new(
+                            line,
+                            format!("Unknown update type {:?}", other),
+                        ))
+                    }
+                });
+            } else {
+                instructions.push(parse_instruction(line, &tokens)?);
+            }
+        }
+
+        let update_type =
+            update_type.ok_or_else(|| ManifestError::new(0, "Missing type instruction"))?;
+        Ok(Manifest {
+            update_type,
+            instructions,
+        })
+    }
+
+    /// Checks that every instruction can be written to a manifest and parsed again.
+    ///
+    /// Errors give the line the instruction would be written to.
+    pub fn validate(&self) -> Result<(), ManifestError> {
+        for (i, instruction) in self.instructions.iter().enumerate() {
+            // The type instruction is written first.
+            instruction.validate_line(i + 2)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "type \"{}\"", self.update_type.as_str())?;
+        for instruction in &self.instructions {
+            writeln!(f, "{}", instruction)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses a list of instructions without a type, as found in the `precomplete` file of an
+/// installation.
+pub fn parse_instructions(text: &str) -> Result<Vec<Instruction>, ManifestError> {
+    lines(text)
+        .map(|(line, tokens)| parse_instruction(line, &tokens?))
+        .collect()
+}
+
+/// An error found while parsing a manifest.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ManifestError {
+    /// The line the error was found on, starting from 1, or 0 if it applies to the whole manifest.
+    pub line: usize,
+    /// A description of the error.
+    pub message: String,
+}
+
+impl ManifestError {
+    fn new(line: usize, message: impl Into<String>) -> Self {
+        Self {
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.line > 0 {
+            write!(f, "Manifest line {}: {}", self.line, self.message)
+        } else {
+            write!(f, "Manifest: {}", self.message)
+        }
+    }
+}
+
+impl Error for ManifestError {}
+
+impl From<ManifestError> for io::Error {
+    fn from(error: ManifestError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, error)
+    }
+}
+
+/// Splits the non-empty, non-comment lines of a manifest into tokens, with line numbers.
+fn lines(text: &str) -> impl Iterator<Item = (usize, Result<Vec<&str>, ManifestError>)> {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(line, text)| (line, tokenize(line, text)))
+}
+
+/// Splits a line into the instruction name followed by its quoted arguments.
+fn tokenize(line: usize, text: &str) -> Result<Vec<&str>, ManifestError> {
+    let (name, mut rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    let mut tokens = vec![name];
+
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            return Ok(tokens);
+        }
+
+        let quoted = rest.strip_prefix('"').ok_or_else(|| {
+            ManifestError::new(line, format!("Expected a quoted argument at {:?}", rest))
+        })?;
+        let (token, remainder) = quoted
+            .split_once('"')
+            .ok_or_else(|| ManifestError::new(line, "Unterminated quoted argument"))?;
+        if token.is_empty() {
+            return Err(ManifestError::new(line, "Empty argument"));
+        }
+        tokens.push(token);
+        rest = remainder;
+    }
+}
+
+/// Checks that an instruction has exactly `N` arguments.
+fn args<'a, const N: usize>(
+    line: usize,
+    tokens: &[&'a str],
+) -> Result<[&'a str; N], ManifestError> {
+    tokens[1..].try_into().map_err(|_| {
+        ManifestError::new(
+            line,
+            format!(
+                "{} expects {} argument{} but found {}",
+                tokens[0],
+                N,
+                if N == 1 { "" } else { "s" },
+                tokens.len() - 1
+            ),
+        )
+    })
+}
+
+fn parse_instruction(line: usize, tokens: &[&str]) -> Result<Instruction, ManifestError> {
+    let instruction = match tokens[0] {
+        "add" => {
+            let [path] = args(line, tokens)?;
+            Instruction::Add { path: path.into() }
+        }
+        "add-if" => {
+            let [test, path] = args(line, tokens)?;
+            Instruction::AddIf {
+                test: test.into(),
+                path: path.into(),
+            }
+        }
+        "add-if-not" => {
+            let [test, path] = args(line, tokens)?;
+            Instruction::AddIfNot {
+                test: test.into(),
+                path: path.into(),
+            }
+        }
+        "patch" => {
+            let [patch, path] = args(line, tokens)?;
+            Instruction::Patch {
+                patch: patch.into(),
+                path: path.into(),
+            }
+        }
+        "patch-if" => {
+            let [test, patch, path] = args(line, tokens)?;
+            Instruction::PatchIf {
+                test: test.into(),
+                patch: patch.into(),
+                path: path.into(),
+            }
+        }
+        "remove" => {
+            let [path] = args(line, tokens)?;
+            Instruction::Remove { path: path.into() }
+        }
+        "rmdir" => {
+            let [path] = args(line, tokens)?;
+            Instruction::RemoveDir { path: path.into() }
+        }
+        "rmrfdir" => {
+            let [path] = args(line, tokens)?;
+            Instruction::RemoveDirRecursive { path: path.into() }
+        }
+        other => {
+            return Err(ManifestError::new(
+                line,
+                format!("Unknown instruction {:?}", other),
+            ))
+        }
+    };
+
+    Ok(instruction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest() -> Manifest {
+        Manifest {
+            update_type: UpdateType::Partial,
+            instructions: vec![
+                Instruction::Add {
+                    path: "a b/c.txt".to_owned(),
+                },
+                Instruction::AddIf {
+                    test: "extensions/".to_owned(),
+                    path: "extensions/x.xpi".to_owned(),
+                },
+                Instruction::AddIfNot {
+                    test: "channel-prefs.js".to_owned(),
+                    path: "channel-prefs.js".to_owned(),
+                },
+                Instruction::Patch {
+                    patch: "lib.so.patch".to_owned(),
+                    path: "lib.so".to_owned(),
+                },
+                Instruction::PatchIf {
+                    test: "extensions/".to_owned(),
+                    patch: "extensions/y.xpi.patch".to_owned(),
+                    path: "extensions/y.xpi".to_owned(),
+                },
+                Instruction::Remove {
+                    path: "old.txt".to_owned(),
+                },
+                Instruction::RemoveDir {
+                    path: "old/".to_owned(),
+                },
+                Instruction::RemoveDirRecursive {
+                    path: "older/".to_owned(),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let manifest = manifest();
+        manifest.validate().unwrap();
+        assert_eq!(Manifest::parse(&manifest.to_string()).unwrap(), manifest);
+    }
+
+    #[test]
+    fn validate_rejects_unwritable_paths() {
+        for path in ["a\"b.txt", "a\nb.txt", "a\rb.txt", ""] {
+            let mut manifest = manifest();
+            manifest.instructions.push(Instruction::Add {
+                path: path.to_owned(),
+            });
+            let error = manifest.validate().unwrap_err();
+            assert_eq!(error.line, 10, "{:?}", path);
+            assert!(Instruction::Add {
+                path: path.to_owned()
+            }
+            .validate()
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(
+            Manifest::parse("add \"a\"\n").unwrap_err(),
+            ManifestError::new(0, "Missing type instruction")
+        );
+        assert_eq!(
+            Manifest::parse("type \"complete\"\nadd \"a\" \"b\"\n")
+                .unwrap_err()
+                .line,
+            2
+        );
+        assert_eq!(
+            Manifest::parse("type \"complete\"\n\n# comment\nadd \"a\n")
+                .unwrap_err()
+                .message,
+            "Unterminated quoted argument"
+        );
+    }
+}
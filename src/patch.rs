@@ -0,0 +1,599 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Creating and applying the binary patches found in partial update MAR files.
+//!
+//! Patches use Mozilla's `MBDIFF10` variant of bsdiff. A patch starts with a header, all fields
+//! big-endian:
+//!
+//! ```text
+//! "MBDIFF10"  magic
+//! u32         length of the source file
+//! u32         CRC32 of the source file
+//! u32         length of the destination file
+//! u32         length of the control block
+//! u32         length of the diff block
+//! u32         length of the extra block
+//! ```
+//!
+//! This is followed by the three blocks, uncompressed. The control block is a list of `(x, y, z)`
+//! triples: add `x` bytes from the diff block to `x` bytes of the source, copy `y` bytes from the
+//! extra block, then move `z` bytes (which may be negative) through the source.
+
+use std::io::{ErrorKind, Read, Write};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::{MarError, Result};
+
+/// Magic bytes at the start of a patch.
+pub const PATCH_ID: &[u8; PATCH_ID_SIZE] = b"MBDIFF10";
+const PATCH_ID_SIZE: usize = 8;
+
+/// Size of the header including the magic bytes.
+const HEADER_SIZE: usize = PATCH_ID_SIZE + 6 * 4;
+
+/// Size of a single control triple.
+const CONTROL_SIZE: usize = 12;
+
+/// The header of a patch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PatchHeader {
+    /// The length of the source file.
+    pub source_length: u32,
+    /// The CRC32 of the source file.
+    pub source_crc32: u32,
+    /// The length of the destination file.
+    pub dest_length: u32,
+    /// The length of the control block.
+    pub control_length: u32,
+    /// The length of the diff block.
+    pub diff_length: u32,
+    /// The length of the extra block.
+    pub extra_length: u32,
+}
+
+impl PatchHeader {
+    /// Reads a patch header, checking the magic bytes.
+    pub fn read<R: Read>(mut patch: R) -> Result<PatchHeader> {
+        let mut header = [0; HEADER_SIZE];
+        patch.read_exact(&mut header).map_err(|e| match e.kind() {
+            ErrorKind::UnexpectedEof => invalid_patch("Patch is shorter than its header"),
+            _ => e.into(),
+        })?;
+        if &header[..PATCH_ID_SIZE] != PATCH_ID {
+            return Err(invalid_patch("Not an MBDIFF10 patch"));
+        }
+
+        let mut fields = &header[PATCH_ID_SIZE..];
+        Ok(PatchHeader {
+            source_length: fields.read_u32::<BigEndian>()?,
+            source_crc32: fields.read_u32::<BigEndian>()?,
+            dest_length: fields.read_u32::<BigEndian>()?,
+            control_length: fields.read_u32::<BigEndian>()?,
+            diff_length: fields.read_u32::<BigEndian>()?,
+            extra_length: fields.read_u32::<BigEndian>()?,
+        })
+    }
+
+    /// Writes the header, including the magic bytes.
+    pub fn write<W: Write>(&self, mut output: W) -> Result<()> {
+        output.write_all(PATCH_ID)?;
+        output.write_u32::<BigEndian>(self.source_length)?;
+        output.write_u32::<BigEndian>(self.source_crc32)?;
+        output.write_u32::<BigEndian>(self.dest_length)?;
+        output.write_u32::<BigEndian>(self.control_length)?;
+        output.write_u32::<BigEndian>(self.diff_length)?;
+        output.write_u32::<BigEndian>(self.extra_length)?;
+        Ok(())
+    }
+}
+
+/// Applies a patch to the contents of `source`, writing the result to `dest`.
+///
+/// The source is checked against the length and CRC32 recorded in the patch before anything is
+/// written.
+pub fn apply_patch<S, P, D>(mut source: S, mut patch: P, mut dest: D) -> Result<()>
+where
+    S: Read,
+    P: Read,
+    D: Write,
+{
+    let mut old = Vec::new();
+    source.read_to_end(&mut old)?;
+    let mut data = Vec::new();
+    patch.read_to_end(&mut data)?;
+
+    Ok(dest.write_all(&patch_bytes(&old, &data)?)?)
+}
+
+/// Applies a patch held in memory to a source held in memory, returning the result.
+pub(crate) fn patch_bytes(old: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+    let header = PatchHeader::read(patch)?;
+
+    let blocks = &patch[HEADER_SIZE..];
+    let control_length = header.control_length as usize;
+    let diff_length = header.diff_length as usize;
+    let extra_length = header.extra_length as usize;
+    if control_length as u64 + diff_length as u64 + extra_length as u64 != blocks.len() as u64 {
+        return Err(invalid_patch("Patch size does not match its header"));
+    }
+    if header.dest_length as u64 > diff_length as u64 + extra_length as u64 {
+        return Err(invalid_patch(
+            "Patch has too little data for its destination length",
+        ));
+    }
+    if !control_length.is_multiple_of(CONTROL_SIZE) {
+        return Err(invalid_patch(
+            "Control block is not a whole number of entries",
+        ));
+    }
+
+    if old.len() as u64 != header.source_length as u64 {
+        return Err(invalid_patch(
+            "Source file has the wrong length for this patch",
+        ));
+    }
+    if crc32fast::hash(old) != header.source_crc32 {
+        return Err(invalid_patch(
+            "Source file has the wrong CRC32 for this patch",
+        ));
+    }
+
+    let (mut control, rest) = blocks.split_at(control_length);
+    let (mut diff, mut extra) = rest.split_at(diff_length);
+
+    let dest_length = header.dest_length as usize;
+    let mut new = Vec::with_capacity(dest_length);
+    let mut old_pos: usize = 0;
+
+    while !control.is_empty() {
+        let x = control.read_u32::<BigEndian>()? as usize;
+        let y = control.read_u32::<BigEndian>()? as usize;
+        let z = control.read_i32::<BigEndian>()?;
+
+        if x > diff.len() || x > dest_length - new.len() || x > old.len() - old_pos {
+            return Err(invalid_patch("Diff data out of bounds"));
+        }
+        new.extend(
+            diff[..x]
+                .iter()
+                .zip(&old[old_pos..old_pos + x])
+                .map(|(d, o)| d.wrapping_add(*o)),
+        );
+        diff = &diff[x..];
+        old_pos += x;
+
+        if y > extra.len() || y > dest_length - new.len() {
+            return Err(invalid_patch("Extra data out of bounds"));
+        }
+        new.extend_from_slice(&extra[..y]);
+        extra = &extra[y..];
+
+        old_pos = old_pos
+            .checked_add_signed(z as isize)
+            .filter(|pos| *pos <= old.len())
+            .ok_or_else(|| invalid_patch("Source position out of bounds"))?;
+    }
+
+    if new.len() != dest_length {
+        return Err(invalid_patch("Patch produced the wrong length of output"));
+    }
+
+    Ok(new)
+}
+
+/// Creates a patch that transforms `old` into `new`.
+///
+/// This is the bsdiff algorithm using Larsson and Sadakane's qsufsort, producing the same
+/// `MBDIFF10` output as Mozilla's `mbsdiff` tool.
+pub fn create_patch(old: &[u8], new: &[u8]) -> Result<Vec<u8>> {
+    if old.len() >= i32::MAX as usize || new.len() >= i32::MAX as usize {
+        return Err(MarError::InvalidInput(
+            "Files must be smaller than 2GB to be patched".to_owned(),
+        ));
+    }
+
+    let suffixes = qsufsort(old);
+    let old_size = old.len() as isize;
+    let new_size = new.len() as isize;
+    let old_at = |i: isize| old[i as usize];
+    let new_at = |i: isize| new[i as usize];
+
+    let mut control = Vec::new();
+    let mut diff = Vec::new();
+    let mut extra = Vec::new();
+
+    let mut scan: isize = 0;
+    let mut len: isize = 0;
+    let mut pos: isize = 0;
+    let mut last_scan: isize = 0;
+    let mut last_pos: isize = 0;
+    let mut last_offset: isize = 0;
+
+    while scan < new_size {
+        let mut old_score: isize = 0;
+        scan += len;
+        let mut scsc = scan;
+
+        // Find the next position where an exact match is better than extending the last one.
+        while scan < new_size {
+            let (found_pos, found_len) = search(&suffixes, old, &new[scan as usize..]);
+            pos = found_pos as isize;
+            len = found_len as isize;
+
+            while scsc < scan + len {
+                if scsc + last_offset < old_size && old_at(scsc + last_offset) == new_at(scsc) {
+                    old_score += 1;
+                }
+                scsc += 1;
+            }
+
+            if (len == old_score && len != 0) || len > old_score + 8 {
+                break;
+            }
+
+            if scan + last_offset < old_size && old_at(scan + last_offset) == new_at(scan) {
+                old_score -= 1;
+            }
+            scan += 1;
+        }
+
+        if len != old_score || scan == new_size {
+            // Extend the last match forwards.
+            let mut score = 0;
+            let mut best = 0;
+            let mut len_forward = 0;
+            let mut i = 0;
+            while last_scan + i < scan && last_pos + i < old_size {
+                if old_at(last_pos + i) == new_at(last_scan + i) {
+                    score += 1;
+                }
+                i += 1;
+                if score * 2 - i > best * 2 - len_forward {
+                    best = score;
+                    len_forward = i;
+                }
+            }
+
+            // Extend the new match backwards.
+            let mut len_back = 0;
+            if scan < new_size {
+                let mut score = 0;
+                let mut best = 0;
+                let mut i = 1;
+                while scan >= last_scan + i && pos >= i {
+                    if old_at(pos - i) == new_at(scan - i) {
+                        score += 1;
+                    }
+                    if score * 2 - i > best * 2 - len_back {
+                        best = score;
+                        len_back = i;
+                    }
+                    i += 1;
+                }
+            }
+
+            // Split any overlap between the two extensions.
+            if last_scan + len_forward > scan - len_back {
+                let overlap = (last_scan + len_forward) - (scan - len_back);
+                let mut score = 0;
+                let mut best = 0;
+                let mut split = 0;
+                for i in 0..overlap {
+                    if new_at(last_scan + len_forward - overlap + i)
+                        == old_at(last_pos + len_forward - overlap + i)
+                    {
+                        score += 1;
+                    }
+                    if new_at(scan - len_back + i) == old_at(pos - len_back + i) {
+                        score -= 1;
+                    }
+                    if score > best {
+                        best = score;
+                        split = i + 1;
+                    }
+                }
+                len_forward += split - overlap;
+                len_back -= split;
+            }
+
+            diff.extend(
+                (0..len_forward).map(|i| new_at(last_scan + i).wrapping_sub(old_at(last_pos + i))),
+            );
+            let extra_start = last_scan + len_forward;
+            let extra_end = scan - len_back;
+            extra.extend_from_slice(&new[extra_start as usize..extra_end as usize]);
+
+            control.write_u32::<BigEndian>(len_forward as u32)?;
+            control.write_u32::<BigEndian>((extra_end - extra_start) as u32)?;
+            control.write_i32::<BigEndian>(((pos - len_back) - (last_pos + len_forward)) as i32)?;
+
+            last_scan = scan - len_back;
+            last_pos = pos - len_back;
+            last_offset = pos - scan;
+        }
+    }
+
+    let header = PatchHeader {
+        source_length: old.len() as u32,
+        source_crc32: crc32fast::hash(old),
+        dest_length: new.len() as u32,
+        control_length: to_u32(control.len())?,
+        diff_length: to_u32(diff.len())?,
+        extra_length: to_u32(extra.len())?,
+    };
+
+    let mut patch = Vec::with_capacity(HEADER_SIZE + control.len() + diff.len() + extra.len());
+    header.write(&mut patch)?;
+    patch.extend_from_slice(&control);
+    patch.extend_from_slice(&diff);
+    patch.extend_from_slice(&extra);
+    Ok(patch)
+}
+
+/// Builds the suffix array of `old`, including the empty suffix at `old.len()`.
+fn qsufsort(old: &[u8]) -> Vec<i32> {
+    let size = old.len();
+    let mut buckets = [0i32; 256];
+    for &byte in old {
+        buckets[byte as usize] += 1;
+    }
+    for i in 1..256 {
+        buckets[i] += buckets[i - 1];
+    }
+    for i in (1..256).rev() {
+        buckets[i] = buckets[i - 1];
+    }
+    buckets[0] = 0;
+
+    let mut suffixes = vec![0i32; size + 1];
+    let mut ranks = vec![0i32; size + 1];
+    for (i, &byte) in old.iter().enumerate() {
+        buckets[byte as usize] += 1;
+        suffixes[buckets[byte as usize] as usize] = i as i32;
+    }
+    suffixes[0] = size as i32;
+    for (i, &byte) in old.iter().enumerate() {
+        ranks[i] = buckets[byte as usize];
+    }
+    ranks[size] = 0;
+    for i in 1..256 {
+        if buckets[i] == buckets[i - 1] + 1 {
+            suffixes[buckets[i] as usize] = -1;
+        }
+    }
+    suffixes[0] = -1;
+
+    // Sorted groups are marked by negative lengths, keep doubling the prefix length until
+    // everything is sorted.
+    let mut h = 1;
+    while suffixes[0] != -(size as i32 + 1) {
+        let mut len: i32 = 0;
+        let mut i = 0;
+        while i < size + 1 {
+            if suffixes[i] < 0 {
+                len -= suffixes[i];
+                i += -suffixes[i] as usize;
+            } else {
+                if len != 0 {
+                    suffixes[i - len as usize] = -len;
+                }
+                let group = (ranks[suffixes[i] as usize] + 1) as usize - i;
+                split(&mut suffixes, &mut ranks, i, group, h);
+                i += group;
+                len = 0;
+            }
+        }
+        if len != 0 {
+            suffixes[i - len as usize] = -len;
+        }
+        h += h;
+    }
+
+    for (i, &rank) in ranks.iter().enumerate() {
+        suffixes[rank as usize] = i as i32;
+    }
+    suffixes
+}
+
+/// Sorts a group of suffixes that share their first `h` bytes.
+fn split(suffixes: &mut [i32], ranks: &mut [i32], start: usize, len: usize, h: usize) {
+    let key = |suffixes: &[i32], ranks: &[i32], i: usize| ranks[suffixes[i] as usize + h];
+
+    if len < 16 {
+        let mut k = start;
+        while k < start + len {
+            let mut j = 1;
+            let mut x = key(suffixes, ranks, k);
+            let mut i = 1;
+            while k + i < start + len {
+                let value = key(suffixes, ranks, k + i);
+                if value < x {
+                    x = value;
+                    j = 0;
+                }
+                if value == x {
+                    suffixes.swap(k + j, k + i);
+                    j += 1;
+                }
+                i += 1;
+            }
+            for i in 0..j {
+                ranks[suffixes[k + i] as usize] = (k + j - 1) as i32;
+            }
+            if j == 1 {
+                suffixes[k] = -1;
+            }
+            k += j;
+        }
+        return;
+    }
+
+    let x = key(suffixes, ranks, start + len / 2);
+    let mut less = 0;
+    let mut equal = 0;
+    for i in start..start + len {
+        let value = key(suffixes, ranks, i);
+        if value < x {
+            less += 1;
+        }
+        if value == x {
+            equal += 1;
+        }
+    }
+    let jj = start + less;
+    let kk = jj + equal;
+
+    let mut i = start;
+    let mut j = 0;
+    let mut k = 0;
+    while i < jj {
+        let value = key(suffixes, ranks, i);
+        if value < x {
+            i += 1;
+        } else if value == x {
+            suffixes.swap(i, jj + j);
+            j += 1;
+        } else {
+            suffixes.swap(i, kk + k);
+            k += 1;
+        }
+    }
+    while jj + j < kk {
+        if key(suffixes, ranks, jj + j) == x {
+            j += 1;
+        } else {
+            suffixes.swap(jj + j, kk + k);
+            k += 1;
+        }
+    }
+
+    if jj > start {
+        split(suffixes, ranks, start, jj - start, h);
+    }
+    for i in 0..kk - jj {
+        ranks[suffixes[jj + i] as usize] = (kk - 1) as i32;
+    }
+    if jj == kk - 1 {
+        suffixes[jj] = -1;
+    }
+    if start + len > kk {
+        split(suffixes, ranks, kk, start + len - kk, h);
+    }
+}
+
+/// Finds the longest match for the start of `new` in `old`, returning its position and length.
+fn search(suffixes: &[i32], old: &[u8], new: &[u8]) -> (usize, usize) {
+    let match_len = |start: usize| {
+        old[start..]
+            .iter()
+            .zip(new)
+            .take_while(|(a, b)| a == b)
+            .count()
+    };
+
+    let mut st = 0;
+    let mut en = old.len();
+    while en - st >= 2 {
+        let mid = st + (en - st) / 2;
+        let start = suffixes[mid] as usize;
+        let n = (old.len() - start).min(new.len());
+        if old[start..start + n] < new[..n] {
+            st = mid;
+        } else {
+            en = mid;
+        }
+    }
+
+    let x = match_len(suffixes[st] as usize);
+    let y = match_len(suffixes[en] as usize);
+    if x > y {
+        (suffixes[st] as usize, x)
+    } else {
+        (suffixes[en] as usize, y)
+    }
+}
+
+fn to_u32(len: usize) -> Result<u32> {
+    u32::try_from(len).map_err(|_| MarError::InvalidInput("Patch is too large".to_owned()))
+}
+
+fn invalid_patch(message: &str) -> MarError {
+    MarError::InvalidPatch(message.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns `len` bytes of deterministic pseudo-random data.
+    fn noise(seed: u32, len: usize) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    fn round_trip(old: &[u8], new: &[u8]) {
+        let patch = create_patch(old, new).unwrap();
+        let mut output = Vec::new();
+        apply_patch(old, &patch[..], &mut output).unwrap();
+        assert_eq!(output, new);
+    }
+
+    fn invalid_patch_message(result: Result<Vec<u8>>) -> String {
+        match result {
+            Err(MarError::InvalidPatch(message)) => message,
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn patch_round_trip() {
+        let old = noise(1, 10_000);
+        let mut new = old.clone();
+        new[100..200].copy_from_slice(&noise(2, 100));
+        new.splice(5000..5000, noise(3, 300));
+        new.truncate(9000);
+
+        round_trip(&old, &new);
+        round_trip(&old, &old);
+        round_trip(&old, b"");
+        round_trip(b"", &new);
+        round_trip(b"", b"");
+    }
+
+    #[test]
+    fn truncated_header() {
+        let patch = create_patch(b"old", b"new").unwrap();
+        let message = invalid_patch_message(patch_bytes(b"old", &patch[..HEADER_SIZE - 1]));
+        assert_eq!(message, "Patch is shorter than its header");
+    }
+
+    #[test]
+    fn overflowing_header() {
+        let patch = create_patch(b"old", b"new").unwrap();
+        let mut header = PatchHeader::read(&patch[..]).unwrap();
+        header.control_length = u32::MAX;
+        header.diff_length = u32::MAX;
+        header.extra_length = u32::MAX;
+        let mut bad = Vec::new();
+        header.write(&mut bad).unwrap();
+        bad.extend_from_slice(&patch[HEADER_SIZE..]);
+
+        let message = invalid_patch_message(patch_bytes(b"old", &bad));
+        assert_eq!(message, "Patch size does not match its header");
+    }
+
+    #[test]
+    fn wrong_source() {
+        let patch = create_patch(b"old", b"new").unwrap();
+        let message = invalid_patch_message(patch_bytes(b"odd", &patch));
+        assert_eq!(message, "Source file has the wrong CRC32 for this patch");
+    }
+}
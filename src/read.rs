@@ -4,20 +4,56 @@
 
 //! Low level utilities for reading MAR files.
 
-use super::{MarFileInfo, MarItem};
+use super::{AdditionalBlock, MarFileInfo, MarItem, ProductInformation};
+use crate::error::{truncated, MarError, Result};
+use crate::signing::Signature;
+use crate::write::PRODUCT_INFO_BLOCK_ID;
 use byteorder::{BigEndian, ReadBytesExt};
-use std::io::{self, BufRead, ErrorKind, Read, Seek, SeekFrom};
-use std::u32;
+use std::io::{BufRead, Read, Seek, SeekFrom};
 
 /// Magic bytes found at the start of a MAR file.
-const MAR_ID: &[u8; MAR_ID_SIZE] = b"MAR1";
-const MAR_ID_SIZE: usize = 4;
+pub(crate) const MAR_ID: &[u8; MAR_ID_SIZE] = b"MAR1";
+pub(crate) const MAR_ID_SIZE: usize = 4;
 
 /// Position of the signature block within the file, directly after the 16-byte header.
-const SIGNATURE_BLOCK_OFFSET: u64 = 16;
+pub(crate) const SIGNATURE_BLOCK_OFFSET: u64 = 16;
+
+/// Maximum number of signatures Firefox will accept in a signature block.
+pub(crate) const MAX_SIGNATURES: u32 = 8;
+
+/// Maximum length of a single signature Firefox will accept.
+pub(crate) const MAX_SIGNATURE_LENGTH: u32 = 2048;
+
+/// The furthest into a file `info_from_offsets` can read: the header, a full signature block and
+/// the count of additional blocks.
+#[cfg(feature = "tokio")]
+pub(crate) const MAX_INFO_SIZE: u64 =
+    SIGNATURE_BLOCK_OFFSET + 4 + MAX_SIGNATURES as u64 * (8 + MAX_SIGNATURE_LENGTH as u64) + 4;
+
+/// Options controlling how the index of a MAR file is read.
+#[derive(Clone, Debug, Default)]
+pub struct ReadOptions {
+    non_utf8_names: bool,
+}
+
+impl ReadOptions {
+    /// Creates the default options, which reject names that are not valid UTF-8.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets whether to accept entries whose names are not valid UTF-8.
+    ///
+    /// Such names are converted lossily for `MarItem::name`, and the raw bytes are available
+    /// from `MarItem::name_bytes`.
+    pub fn non_utf8_names(&mut self, non_utf8_names: bool) -> &mut Self {
+        self.non_utf8_names = non_utf8_names;
+        self
+    }
+}
 
 /// Read metadata from a MAR file.
-pub fn get_info<R>(mut archive: R) -> io::Result<MarFileInfo>
+pub fn get_info<R>(mut archive: R) -> Result<MarFileInfo>
 where
     R: Read + Seek,
 {
@@ -25,47 +61,73 @@ where
     archive.rewind()?;
 
     // Read the header.
-    let mut id = [0; MAR_ID_SIZE];
-    archive.read_exact(&mut id)?;
-    if id != *MAR_ID {
-        return Err(io::Error::new(
-            ErrorKind::InvalidData,
-            "Not a MAR file (invalid bytes at start of file).",
-        ));
-    }
-    let offset_to_index = archive.read_u32::<BigEndian>()?;
-    let num_signatures = archive.read_u32::<BigEndian>()?;
+    let offset_to_index = read_header(&mut archive)?;
 
-    // Seek to the index and read the first offset to content field.
-    archive.seek(SeekFrom::Start(offset_to_index as u64))?;
-    let offset_to_content = archive.read_u32::<BigEndian>()?;
+    // Read the first offset to content field from the index. An empty index means the content
+    // (of which there is none) ends where the index starts.
+    let size_of_index = seek_index(&mut archive, offset_to_index)?;
+    let offset_to_content = if size_of_index >= 4 {
+        read_u32(&mut archive)?
+    } else {
+        offset_to_index
+    };
+
+    info_from_offsets(archive, offset_to_index, offset_to_content)
+}
 
+/// Read the rest of the metadata from a MAR file given the offsets to its index and content.
+pub(crate) fn info_from_offsets<R>(
+    mut archive: R,
+    offset_to_index: u32,
+    offset_to_content: u32,
+) -> Result<MarFileInfo>
+where
+    R: Read + Seek,
+{
     // In an old-style MAR file with no signature block, the content will start right after the
     // magic bytes and the 4-byte index offset.
     let has_signature_block = offset_to_content as usize != MAR_ID_SIZE + 4;
+    if !has_signature_block {
+        return Ok(MarFileInfo {
+            offset_to_index,
+            has_signature_block,
+            num_signatures: 0,
+            offset_additional_blocks: 0,
+            has_additional_blocks: false,
+            num_additional_blocks: 0,
+        });
+    }
 
     // Seek to the signature block and skip past all the signatures.
     archive.seek(SeekFrom::Start(SIGNATURE_BLOCK_OFFSET))?;
+    let num_signatures = read_u32(&mut archive)?;
+    if num_signatures > MAX_SIGNATURES {
+        return Err(MarError::Malformed(
+            "Too many signatures in the signature block".to_owned(),
+        ));
+    }
     for _ in 0..num_signatures {
         archive.seek(SeekFrom::Current(4))?;
-        let signature_len = archive.read_u32::<BigEndian>()?;
+        let signature_len = read_u32(&mut archive)?;
+        if signature_len > MAX_SIGNATURE_LENGTH {
+            return Err(MarError::Malformed("Signature is too long".to_owned()));
+        }
         archive.seek(SeekFrom::Current(signature_len as i64))?;
     }
 
-    // Check for additional blocks.
-    let pos = archive.seek(SeekFrom::Current(0))?;
+    // If the content doesn't start directly after the signatures then there are additional
+    // blocks.
+    let pos = archive.stream_position()?;
     if pos > u32::MAX as u64 {
-        return Err(io::Error::new(
-            ErrorKind::InvalidData,
-            "Signature block size overflow",
+        return Err(MarError::Malformed(
+            "Signature block size overflow".to_owned(),
         ));
     }
-    let offset_additional_blocks = pos as u32;
-    let has_additional_blocks = offset_additional_blocks == offset_to_content;
-    let num_additional_blocks = if has_additional_blocks {
-        archive.read_u32::<BigEndian>()?
+    let has_additional_blocks = pos != offset_to_content as u64;
+    let (num_additional_blocks, offset_additional_blocks) = if has_additional_blocks {
+        (read_u32(&mut archive)?, pos as u32 + 4)
     } else {
-        0
+        (0, 0)
     };
 
     Ok(MarFileInfo {
@@ -78,61 +140,361 @@ where
     })
 }
 
+/// Checks the magic bytes at the start of a MAR file and returns the offset to the index.
+fn read_header<R: Read + Seek>(mut archive: R) -> Result<u32> {
+    let mut header = Vec::with_capacity(MAR_ID_SIZE + 4);
+    archive
+        .by_ref()
+        .take(MAR_ID_SIZE as u64 + 4)
+        .read_to_end(&mut header)?;
+    parse_header(&header)
+}
+
+/// Checks the magic bytes in the first bytes of a MAR file and returns the offset to the index.
+pub(crate) fn parse_header(mut header: &[u8]) -> Result<u32> {
+    let mut id = [0; MAR_ID_SIZE];
+    header.read_exact(&mut id).map_err(truncated)?;
+    if id != *MAR_ID {
+        return Err(MarError::BadMagic);
+    }
+    read_u32(header)
+}
+
+/// Reads a field of the archive's structure, treating the end of the file as corruption.
+fn read_u32<R: Read>(mut archive: R) -> Result<u32> {
+    archive.read_u32::<BigEndian>().map_err(truncated)
+}
+
+/// Checks that the index of a MAR file lies within the file and returns its size, leaving the
+/// stream positioned at the first entry.
+fn seek_index<R: Read + Seek>(mut archive: R, offset_to_index: u32) -> Result<u32> {
+    let file_size = archive.seek(SeekFrom::End(0))?;
+    check_index_bounds(offset_to_index, 0, file_size)?;
+
+    archive.seek(SeekFrom::Start(offset_to_index as u64))?;
+    let size_of_index = read_u32(&mut archive)?;
+    check_index_bounds(offset_to_index, size_of_index, file_size)?;
+    Ok(size_of_index)
+}
+
+/// Checks that an index of the given size, along with its size field, lies within the file.
+pub(crate) fn check_index_bounds(
+    offset_to_index: u32,
+    size_of_index: u32,
+    file_size: u64,
+) -> Result<()> {
+    let offset = offset_to_index as u64;
+    if offset + 4 + size_of_index as u64 > file_size {
+        return Err(MarError::IndexOutOfBounds { offset });
+    }
+    Ok(())
+}
+
+/// Reads the index of a MAR file into memory, checking that it lies within the file.
+fn read_index_bytes<R: Read + Seek>(mut archive: R, offset_to_index: u32) -> Result<Vec<u8>> {
+    let size_of_index = seek_index(&mut archive, offset_to_index)?;
+    let mut index = vec![0; size_of_index as usize];
+    archive.read_exact(&mut index).map_err(truncated)?;
+    Ok(index)
+}
+
+/// Checks that the stored data of an entry lies within the content of a MAR file.
+pub(crate) fn check_span(info: &MarFileInfo, item: &MarItem) -> Result<()> {
+    let end = item.offset as u64 + item.length as u64;
+    if end > info.offset_to_index as u64 {
+        return Err(MarError::EntryOverlapsIndex {
+            name: item.name.clone(),
+        });
+    }
+    Ok(())
+}
+
+/// Read the signatures from the signature block of a MAR file.
+pub fn read_signatures<R>(mut archive: R) -> Result<Vec<Signature>>
+where
+    R: Read + Seek,
+{
+    let info = get_info(&mut archive)?;
+    read_signatures_with_info(archive, &info)
+}
+
+/// Read the signatures from the signature block of a MAR file given its metadata.
+pub(crate) fn read_signatures_with_info<R>(
+    mut archive: R,
+    info: &MarFileInfo,
+) -> Result<Vec<Signature>>
+where
+    R: Read + Seek,
+{
+    if !info.has_signature_block {
+        return Ok(Vec::new());
+    }
+
+    // Skip past the signature count.
+    archive.seek(SeekFrom::Start(SIGNATURE_BLOCK_OFFSET + 4))?;
+
+    let mut signatures = Vec::with_capacity(info.num_signatures as usize);
+    for _ in 0..info.num_signatures {
+        signatures.push(read_signature(&mut archive)?);
+    }
+    Ok(signatures)
+}
+
+/// Read a single signature from the signature block.
+pub(crate) fn read_signature<R: Read>(mut archive: R) -> Result<Signature> {
+    let algorithm_id = read_u32(&mut archive)?;
+    let signature_len = read_u32(&mut archive)?;
+    if signature_len > MAX_SIGNATURE_LENGTH {
+        return Err(MarError::Malformed("Signature is too long".to_owned()));
+    }
+
+    let mut data = vec![0; signature_len as usize];
+    archive.read_exact(&mut data).map_err(truncated)?;
+    Ok(Signature { algorithm_id, data })
+}
+
+/// Read the product information from the additional blocks of a MAR file.
+pub fn read_product_info<R>(mut archive: R) -> Result<Option<ProductInformation>>
+where
+    R: Read + Seek,
+{
+    let info = get_info(&mut archive)?;
+    read_product_info_with_info(archive, &info)
+}
+
+/// Read the product information from the additional blocks of a MAR file given its metadata.
+pub(crate) fn read_product_info_with_info<R>(
+    archive: R,
+    info: &MarFileInfo,
+) -> Result<Option<ProductInformation>>
+where
+    R: Read + Seek,
+{
+    for block in additional_blocks_with_info(archive, info) {
+        if let AdditionalBlock::ProductInformation(info) = block? {
+            return Ok(Some(info));
+        }
+    }
+    Ok(None)
+}
+
+/// Returns an iterator over the additional blocks of a MAR file.
+pub fn additional_blocks<R>(mut archive: R) -> Result<AdditionalBlocks<R>>
+where
+    R: Read + Seek,
+{
+    let info = get_info(&mut archive)?;
+    Ok(additional_blocks_with_info(archive, &info))
+}
+
+/// Returns an iterator over the additional blocks of a MAR file given its metadata.
+pub(crate) fn additional_blocks_with_info<R>(archive: R, info: &MarFileInfo) -> AdditionalBlocks<R>
+where
+    R: Read + Seek,
+{
+    let remaining = if info.has_additional_blocks {
+        info.num_additional_blocks
+    } else {
+        0
+    };
+
+    AdditionalBlocks {
+        archive,
+        position: info.offset_additional_blocks as u64,
+        remaining,
+    }
+}
+
+/// An iterator over the additional blocks of a MAR file.
+pub struct AdditionalBlocks<R> {
+    archive: R,
+    position: u64,
+    remaining: u32,
+}
+
+impl<R> AdditionalBlocks<R>
+where
+    R: Read + Seek,
+{
+    fn read_block(&mut self) -> Result<AdditionalBlock> {
+        self.archive.seek(SeekFrom::Start(self.position))?;
+        let size = read_u32(&mut self.archive)?;
+        let id = read_u32(&mut self.archive)?;
+        if size < 8 {
+            return Err(MarError::Malformed(
+                "Additional block is too small".to_owned(),
+            ));
+        }
+
+        let mut data = vec![0; size as usize - 8];
+        self.archive.read_exact(&mut data).map_err(truncated)?;
+        self.position += size as u64;
+
+        parse_additional_block(id, data)
+    }
+}
+
+impl<R> Iterator for AdditionalBlocks<R>
+where
+    R: Read + Seek,
+{
+    type Item = Result<AdditionalBlock>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        let result = self.read_block();
+        // Stop after an error since the position of the next block is unknown.
+        self.remaining = if result.is_ok() {
+            self.remaining - 1
+        } else {
+            0
+        };
+        Some(result)
+    }
+}
+
+/// Parses the content of an additional block with the given identifier.
+pub(crate) fn parse_additional_block(id: u32, data: Vec<u8>) -> Result<AdditionalBlock> {
+    if id == PRODUCT_INFO_BLOCK_ID {
+        Ok(AdditionalBlock::ProductInformation(parse_product_info(
+            &data,
+        )?))
+    } else {
+        Ok(AdditionalBlock::Unknown { id, data })
+    }
+}
+
+/// Parse the content of a product information block.
+pub(crate) fn parse_product_info(mut data: &[u8]) -> Result<ProductInformation> {
+    let mut strings = Vec::with_capacity(2);
+    for _ in 0..2 {
+        let mut value = Vec::new();
+        data.read_until(0, &mut value)?;
+        if value.pop() != Some(0) {
+            return Err(MarError::Malformed(
+                "Unterminated string in product information block".to_owned(),
+            ));
+        }
+        let value = String::from_utf8(value)
+            .map_err(|_| MarError::Malformed("Product information is not UTF-8".to_owned()))?;
+        strings.push(value);
+    }
+
+    let product_version = strings.pop().unwrap_or_default();
+    let mar_channel_id = strings.pop().unwrap_or_default();
+    Ok(ProductInformation {
+        mar_channel_id,
+        product_version,
+    })
+}
+
+/// Find the first additional block with the given identifier.
+///
+/// Returns the position and size of the block, leaving the stream positioned just after the
+/// block's size and identifier.
+pub(crate) fn find_additional_block<R>(
+    mut archive: R,
+    info: &MarFileInfo,
+    id: u32,
+) -> Result<Option<(u64, u32)>>
+where
+    R: Read + Seek,
+{
+    if !info.has_additional_blocks {
+        return Ok(None);
+    }
+
+    let mut position = info.offset_additional_blocks as u64;
+    for _ in 0..info.num_additional_blocks {
+        archive.seek(SeekFrom::Start(position))?;
+        let size = read_u32(&mut archive)?;
+        let block_id = read_u32(&mut archive)?;
+        if size < 8 {
+            return Err(MarError::Malformed(
+                "Additional block is too small".to_owned(),
+            ));
+        }
+        if block_id == id {
+            return Ok(Some((position, size)));
+        }
+        position += size as u64;
+    }
+
+    Ok(None)
+}
+
 /// Read the index from a MAR file.
 ///
 /// TODO: Return an iterator?
-pub fn read_index<R>(mut archive: R) -> io::Result<Vec<MarItem>>
+pub fn read_index<R>(archive: R) -> Result<Vec<MarItem>>
+where
+    R: Read + Seek,
+{
+    read_index_with_options(archive, &ReadOptions::new())
+}
+
+/// Read the index from a MAR file.
+///
+/// Behaves as `read_index` with the given options.
+pub fn read_index_with_options<R>(mut archive: R, options: &ReadOptions) -> Result<Vec<MarItem>>
 where
     R: Read + Seek,
 {
     // Ensure we're at the start of the stream.
     archive.rewind()?;
 
-    // Verify the magic bytes.
-    let mut id = [0; MAR_ID_SIZE];
-    archive.read_exact(&mut id)?;
-    if id != *MAR_ID {
-        return Err(io::Error::new(
-            ErrorKind::InvalidData,
-            "Not a MAR file (invalid bytes at start of file).",
-        ));
-    }
-
-    // Seek to the index.
-    let offset_to_index = archive.read_u32::<BigEndian>()?;
-    archive.seek(SeekFrom::Start(offset_to_index as u64))?;
+    // Verify the magic bytes and read the index into memory.
+    let offset_to_index = read_header(&mut archive)?;
+    let buf = read_index_bytes(&mut archive, offset_to_index)?;
 
-    // Read the index into memory.
-    let size_of_index = archive.read_u32::<BigEndian>()?;
-    let mut buf = vec![0; size_of_index as usize];
-    archive.read_exact(&mut buf)?;
+    parse_index(&buf, options)
+}
 
-    // Reach each item from the index.
+/// Parse every entry from the bytes of the index.
+pub(crate) fn parse_index(mut buf: &[u8], options: &ReadOptions) -> Result<Vec<MarItem>> {
     let mut items = vec![];
-    let mut buf = &buf[..];
     while !buf.is_empty() {
-        items.push(read_next_item(&mut buf)?);
+        items.push(read_next_item(&mut buf, options)?);
     }
     Ok(items)
 }
 
 /// Read a single entry from the index.
-pub(crate) fn read_next_item<R: BufRead>(mut index: R) -> io::Result<MarItem> {
-    let offset = index.read_u32::<BigEndian>()?;
-    let length = index.read_u32::<BigEndian>()?;
-    let flags = index.read_u32::<BigEndian>()?;
+pub(crate) fn read_next_item<R: BufRead>(mut index: R, options: &ReadOptions) -> Result<MarItem> {
+    let partial = |_| MarError::Malformed("Index ends with a partial entry".to_owned());
+    let offset = index.read_u32::<BigEndian>().map_err(partial)?;
+    let length = index.read_u32::<BigEndian>().map_err(partial)?;
+    let flags = index.read_u32::<BigEndian>().map_err(partial)?;
 
     let mut name = Vec::new();
     index.read_until(0, &mut name)?;
-    name.pop(); // Remove the trailing NUL.
+    if name.pop() != Some(0) {
+        return Err(MarError::Malformed(
+            "Index ends with an unterminated name".to_owned(),
+        ));
+    }
 
-    let name = String::from_utf8(name)
-        .map_err(|_| io::Error::new(ErrorKind::InvalidData, "Filename is not UTF-8"))?;
+    let (name, raw_name) = match String::from_utf8(name) {
+        Ok(name) => (name, None),
+        Err(e) if options.non_utf8_names => {
+            let bytes = e.into_bytes();
+            (String::from_utf8_lossy(&bytes).into_owned(), Some(bytes))
+        }
+        Err(e) => {
+            return Err(MarError::InvalidUtf8Name {
+                bytes: e.into_bytes(),
+            })
+        }
+    };
 
     Ok(MarItem {
         offset,
         length,
         flags,
         name,
+        raw_name,
     })
 }
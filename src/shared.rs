@@ -0,0 +1,452 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Reading MAR files from many threads at once.
+//!
+//! `SharedMar` reads through positional I/O rather than a seekable stream, so reading an entry
+//! only needs a shared reference and any number of entries can be read concurrently.
+
+#[cfg(any(unix, windows))]
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::compression::{header_len, DecompressedRead};
+use crate::read::{check_span, get_info, read_index_with_options, ReadOptions};
+use crate::signing::{self, PublicKey};
+use crate::{validate, Index, MarError, MarFileInfo, MarItem, Result};
+
+/// A source of data that can be read at any position through a shared reference.
+pub trait ReadAt {
+    /// Reads bytes starting at `offset` into `buf`, returning how many bytes were read.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    /// Returns the total size of the data.
+    fn size(&self) -> io::Result<u64>;
+
+    /// Reads exactly enough bytes starting at `offset` to fill `buf`.
+    fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(buf, offset) {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(count) => {
+                    buf = &mut buf[count..];
+                    offset += count as u64;
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+// Other platforms have no positional reads for files.
+#[cfg(any(unix, windows))]
+impl ReadAt for File {
+    #[cfg(unix)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::read_at(self, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        // This moves the file's cursor but nothing here relies on it.
+        std::os::windows::fs::FileExt::seek_read(self, buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+}
+
+impl ReadAt for [u8] {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let start = usize::try_from(offset).map_or(self.len(), |offset| offset.min(self.len()));
+        let count = buf.len().min(self.len() - start);
+        buf[..count].copy_from_slice(&self[start..start + count]);
+        Ok(count)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.len() as u64)
+    }
+}
+
+impl ReadAt for Vec<u8> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.as_slice().read_at(buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.len() as u64)
+    }
+}
+
+impl<T: ReadAt + ?Sized> ReadAt for &T {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        (**self).read_at(buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        (**self).size()
+    }
+}
+
+impl<T: ReadAt + ?Sized> ReadAt for Arc<T> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        (**self).read_at(buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        (**self).size()
+    }
+}
+
+/// A seekable reader over part of a `ReadAt` source.
+///
+/// Each section keeps its own position so sections of the same source can be read independently.
+/// Positions are relative to the start of the section.
+pub struct Section<'a, R: ?Sized> {
+    source: &'a R,
+    start: u64,
+    length: u64,
+    position: u64,
+}
+
+impl<'a, R> Section<'a, R>
+where
+    R: ReadAt + ?Sized,
+{
+    fn new(source: &'a R, start: u64, length: u64) -> Section<'a, R> {
+        Section {
+            source,
+            start,
+            length,
+            position: 0,
+        }
+    }
+}
+
+impl<'a, R> Read for Section<'a, R>
+where
+    R: ReadAt + ?Sized,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.length.saturating_sub(self.position);
+        let count = buf.len().min(remaining.try_into().unwrap_or(usize::MAX));
+        if count == 0 {
+            return Ok(0);
+        }
+
+        let count = self
+            .source
+            .read_at(&mut buf[..count], self.start + self.position)?;
+        self.position += count as u64;
+        Ok(count)
+    }
+}
+
+impl<'a, R> Seek for Section<'a, R>
+where
+    R: ReadAt + ?Sized,
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.length.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+/// An interface to read the contents of a mar file that can be shared between threads.
+///
+/// Unlike `Mar` every method takes `&self`, and the readers returned for entries are
+/// independent of each other.
+pub struct SharedMar<R> {
+    info: MarFileInfo,
+    index: Index,
+    source: R,
+}
+
+impl<R> SharedMar<R>
+where
+    R: ReadAt,
+{
+    /// Creates a SharedMar instance from any positional source.
+    pub fn from_source(source: R) -> Result<SharedMar<R>> {
+        Self::from_source_with_options(source, &ReadOptions::new())
+    }
+
+    /// Creates a SharedMar instance from any positional source.
+    ///
+    /// Behaves as `from_source` with the given options.
+    pub fn from_source_with_options(source: R, options: &ReadOptions) -> Result<SharedMar<R>> {
+        let size = source.size()?;
+        let mut reader = Section::new(&source, 0, size);
+        let info = get_info(&mut reader)?;
+        let index = Index::new(read_index_with_options(&mut reader, options)?);
+
+        Ok(SharedMar {
+            info,
+            index,
+            source,
+        })
+    }
+
+    /// Returns a seekable reader over the whole mar file.
+    ///
+    /// This allows the lower level functions in this crate to be used with a shared mar.
+    pub fn reader(&self) -> Result<Section<'_, R>> {
+        Ok(Section::new(&self.source, 0, self.source.size()?))
+    }
+
+    /// Reads the contents of a file from this mar.
+    pub fn read(&self, item: &MarItem) -> Result<DecompressedRead<Section<'_, R>>> {
+        let mut header = [0_u8; 6];
+        let header_len = header_len(item.length as u64);
+        let raw = self.read_raw(item)?;
+        self.source
+            .read_exact_at(&mut header[..header_len], item.offset as u64)?;
+
+        Ok(DecompressedRead::new(&header, raw))
+    }
+
+    /// Reads the stored bytes of a file from this mar without decompressing them.
+    pub fn read_raw(&self, item: &MarItem) -> Result<Section<'_, R>> {
+        check_span(&self.info, item)?;
+        Ok(Section::new(
+            &self.source,
+            item.offset as u64,
+            item.length as u64,
+        ))
+    }
+
+    /// Reads the contents of the named file from this mar, if it exists.
+    pub fn read_by_name(&self, name: &str) -> Result<Option<DecompressedRead<Section<'_, R>>>> {
+        match self.entry(name) {
+            Some(item) => Ok(Some(self.read(item)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Verifies that every one of the given keys matches a signature in this mar.
+    pub fn verify(&self, keys: &[PublicKey]) -> Result<()> {
+        signing::verify_with_info(self.reader()?, &self.info, keys)
+    }
+
+    /// Checks the structure of this mar as strictly as Firefox does, returning every problem
+    /// found.
+    pub fn validate(&self) -> Result<Vec<MarError>> {
+        validate::validate(self.reader()?, &self.info)
+    }
+}
+
+#[cfg(any(unix, windows))]
+impl SharedMar<File> {
+    /// Creates a SharedMar instance from a local file path.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<SharedMar<File>> {
+        Self::from_source(File::open(path)?)
+    }
+
+    /// Creates a SharedMar instance from a local file path.
+    ///
+    /// Behaves as `from_path` with the given options.
+    pub fn from_path_with_options<P: AsRef<Path>>(
+        path: P,
+        options: &ReadOptions,
+    ) -> Result<SharedMar<File>> {
+        Self::from_source_with_options(File::open(path)?, options)
+    }
+}
+
+impl<R> SharedMar<R> {
+    /// Returns the metadata about this mar.
+    pub fn info(&self) -> &MarFileInfo {
+        &self.info
+    }
+
+    /// Returns the entries in this mar, in the order they appear in the index.
+    pub fn files(&self) -> &[MarItem] {
+        self.index.items()
+    }
+
+    /// Returns the entry with the given name, if there is one.
+    pub fn entry(&self, name: &str) -> Option<&MarItem> {
+        self.index.get(name)
+    }
+
+    /// Returns true if this mar has an entry with the given name.
+    pub fn contains(&self, name: &str) -> bool {
+        self.index.contains(name)
+    }
+
+    /// Returns the number of entries in this mar.
+    pub fn len(&self) -> usize {
+        self.index.items().len()
+    }
+
+    /// Returns true if this mar has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.index.items().is_empty()
+    }
+
+    /// Returns an Iterator over the entries in this mar, ordered by name.
+    pub fn sorted_entries(&self) -> impl Iterator<Item = &MarItem> {
+        self.index.sorted()
+    }
+
+    /// Returns the underlying source.
+    pub fn into_inner(self) -> R {
+        self.source
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::compression::CompressionType;
+    use crate::testing::{self, build_with_signature_slot, bz2};
+    use crate::Mar;
+
+    fn contents() -> Vec<Vec<u8>> {
+        (0..8)
+            .map(|i| format!("entry {} ", i).repeat(1000 * (i + 1)).into_bytes())
+            .collect()
+    }
+
+    fn archive() -> Vec<u8> {
+        let mut builder = testing::builder();
+        for (i, content) in contents().into_iter().enumerate() {
+            let name = format!("dir/{}.txt", i);
+            match i % 4 {
+                0 => builder.add_entry(name, 0o644, io::Cursor::new(content)),
+                1 => builder.add_entry(name, 0o644, io::Cursor::new(bz2(&content))),
+                2 => builder.add_compressed_entry(
+                    name,
+                    0o644,
+                    io::Cursor::new(content),
+                    CompressionType::Xz,
+                ),
+                _ => builder.add_compressed_entry(
+                    name,
+                    0o755,
+                    io::Cursor::new(content),
+                    CompressionType::XzBcj,
+                ),
+            };
+        }
+        build_with_signature_slot(builder)
+    }
+
+    /// Reads every entry on its own thread and compares it with reading through `Mar`.
+    fn check_concurrent_reads<R: ReadAt + Sync>(shared: &SharedMar<R>, data: &[u8]) {
+        let mut mar = Mar::from_buffer(io::Cursor::new(data)).unwrap();
+        let expected: Vec<Vec<u8>> = mar
+            .files()
+            .to_vec()
+            .iter()
+            .map(|item| {
+                let mut content = Vec::new();
+                mar.read(item).unwrap().read_to_end(&mut content).unwrap();
+                content
+            })
+            .collect();
+        assert_eq!(expected, contents());
+
+        thread::scope(|scope| {
+            for (item, expected) in shared.files().iter().zip(&expected) {
+                scope.spawn(move || {
+                    let mut content = Vec::new();
+                    shared
+                        .read(item)
+                        .unwrap()
+                        .read_to_end(&mut content)
+                        .unwrap();
+                    assert_eq!(&content, expected, "{}", item.name);
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn concurrent_reads() {
+        let data = archive();
+        check_concurrent_reads(&SharedMar::from_source(data.clone()).unwrap(), &data);
+        check_concurrent_reads(&SharedMar::from_source(&data[..]).unwrap(), &data);
+        let source: Arc<[u8]> = data.clone().into();
+        check_concurrent_reads(&SharedMar::from_source(source).unwrap(), &data);
+
+        let shared = SharedMar::from_source(data.clone()).unwrap();
+        assert_eq!(shared.len(), 8);
+        assert!(shared.contains("dir/3.txt"));
+        let mut content = Vec::new();
+        shared
+            .read_by_name("dir/1.txt")
+            .unwrap()
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        assert_eq!(content, contents()[1]);
+        assert!(shared.validate().unwrap().is_empty());
+    }
+
+    #[cfg(any(unix, windows))]
+    #[test]
+    fn concurrent_reads_from_file() {
+        let data = archive();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.mar");
+        std::fs::write(&path, &data).unwrap();
+        check_concurrent_reads(&SharedMar::from_path(&path).unwrap(), &data);
+    }
+
+    #[test]
+    fn read_at_bounds() {
+        let data = b"0123456789".to_vec();
+        let mut buf = [0; 4];
+        assert_eq!(data.read_at(&mut buf, 8).unwrap(), 2);
+        assert_eq!(&buf[..2], b"89");
+        assert_eq!(data.read_at(&mut buf, 10).unwrap(), 0);
+        assert_eq!(data.read_at(&mut buf, u64::MAX).unwrap(), 0);
+        data.read_exact_at(&mut buf, 6).unwrap();
+        assert_eq!(&buf, b"6789");
+        let error = data.read_exact_at(&mut buf, 7).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn section_seek() {
+        let data = b"0123456789".to_vec();
+        let mut section = Section::new(&data, 2, 6);
+        let mut content = String::new();
+        section.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "234567");
+
+        assert_eq!(section.seek(SeekFrom::End(-2)).unwrap(), 4);
+        assert_eq!(section.seek(SeekFrom::Current(-1)).unwrap(), 3);
+        content.clear();
+        section.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "567");
+
+        assert!(section.seek(SeekFrom::Current(-10)).is_err());
+        assert_eq!(section.seek(SeekFrom::Start(20)).unwrap(), 20);
+        assert_eq!(section.read(&mut [0; 4]).unwrap(), 0);
+    }
+}
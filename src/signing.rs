@@ -0,0 +1,824 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Signing MAR files and verifying their signatures.
+
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use rsa::pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey};
+use rsa::pkcs8::der::{pem, Decode, Encode};
+use rsa::pkcs8::{DecodePrivateKey, DecodePublicKey};
+use rsa::traits::PublicKeyParts;
+use rsa::{Pkcs1v15Sign, RsaPrivateKey, RsaPublicKey};
+use sha1::{Digest, Sha1};
+use sha2::Sha384;
+use x509_cert::Certificate;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::error::truncated;
+use crate::read::{
+    get_info, parse_additional_block, read_signature, read_signatures, MAR_ID, MAR_ID_SIZE,
+    MAX_SIGNATURES, MAX_SIGNATURE_LENGTH, SIGNATURE_BLOCK_OFFSET,
+};
+use crate::write::write_additional_block;
+use crate::{AdditionalBlock, MarError, MarFileInfo, Result};
+
+/// The algorithms that can be used to sign a MAR file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignatureAlgorithm {
+    /// RSA-PKCS#1 v1.5 with a SHA-1 digest, only used by older MAR files.
+    RsaPkcs1Sha1,
+    /// RSA-PKCS#1 v1.5 with a SHA-384 digest.
+    RsaPkcs1Sha384,
+}
+
+impl SignatureAlgorithm {
+    /// Returns the algorithm for an identifier from the signature block.
+    pub fn from_id(id: u32) -> Option<Self> {
+        match id {
+            1 => Some(SignatureAlgorithm::RsaPkcs1Sha1),
+            2 => Some(SignatureAlgorithm::RsaPkcs1Sha384),
+            _ => None,
+        }
+    }
+
+    /// Returns the identifier used for this algorithm in the signature block.
+    pub fn id(self) -> u32 {
+        match self {
+            SignatureAlgorithm::RsaPkcs1Sha1 => 1,
+            SignatureAlgorithm::RsaPkcs1Sha384 => 2,
+        }
+    }
+}
+
+/// A signature from the signature block of a MAR file.
+#[derive(Clone, Debug)]
+pub struct Signature {
+    /// The identifier of the algorithm used to create the signature.
+    pub algorithm_id: u32,
+    /// The raw signature.
+    pub data: Vec<u8>,
+}
+
+impl Signature {
+    /// Returns the algorithm used to create this signature if it is a known one.
+    pub fn algorithm(&self) -> Option<SignatureAlgorithm> {
+        SignatureAlgorithm::from_id(self.algorithm_id)
+    }
+}
+
+/// An RSA public key used to verify MAR signatures.
+#[derive(Clone, Debug)]
+pub struct PublicKey(RsaPublicKey);
+
+impl PublicKey {
+    /// Loads a key from DER data.
+    ///
+    /// Accepts an X.509 certificate, as used by Firefox, a SubjectPublicKeyInfo or a PKCS#1
+    /// public key.
+    pub fn from_der(der: &[u8]) -> Result<Self> {
+        if let Ok(cert) = Certificate::from_der(der) {
+            let spki = cert
+                .tbs_certificate
+                .subject_public_key_info
+                .to_der()
+                .map_err(invalid_public_key)?;
+            return RsaPublicKey::from_public_key_der(&spki)
+                .map(PublicKey)
+                .map_err(invalid_public_key);
+        }
+
+        RsaPublicKey::from_public_key_der(der)
+            .or_else(|_| RsaPublicKey::from_pkcs1_der(der))
+            .map(PublicKey)
+            .map_err(invalid_public_key)
+    }
+
+    /// Loads a key from PEM data containing any of the formats accepted by `from_der`.
+    pub fn from_pem(pem: &str) -> Result<Self> {
+        let (_, der) = pem::decode_vec(pem.as_bytes()).map_err(invalid_public_key)?;
+        Self::from_der(&der)
+    }
+
+    /// Loads a key from a PEM or DER file.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let data = fs::read(path)?;
+        match std::str::from_utf8(&data) {
+            Ok(text) if text.trim_start().starts_with("-----BEGIN") => Self::from_pem(text),
+            _ => Self::from_der(&data),
+        }
+    }
+
+    fn verify(&self, signature: &Signature, digests: &Digests) -> bool {
+        let result = match signature.algorithm() {
+            Some(SignatureAlgorithm::RsaPkcs1Sha1) => digests.sha1.as_ref().map(|digest| {
+                self.0
+                    .verify(Pkcs1v15Sign::new::<Sha1>(), digest, &signature.data)
+            }),
+            Some(SignatureAlgorithm::RsaPkcs1Sha384) => digests.sha384.as_ref().map(|digest| {
+                self.0
+                    .verify(Pkcs1v15Sign::new::<Sha384>(), digest, &signature.data)
+            }),
+            None => None,
+        };
+
+        matches!(result, Some(Ok(())))
+    }
+}
+
+impl From<RsaPublicKey> for PublicKey {
+    fn from(key: RsaPublicKey) -> Self {
+        PublicKey(key)
+    }
+}
+
+/// An RSA private key used to sign MAR files.
+#[derive(Clone, Debug)]
+pub struct PrivateKey(RsaPrivateKey);
+
+impl PrivateKey {
+    /// Loads a key from PKCS#8 or PKCS#1 DER data.
+    pub fn from_der(der: &[u8]) -> Result<Self> {
+        RsaPrivateKey::from_pkcs8_der(der)
+            .or_else(|_| RsaPrivateKey::from_pkcs1_der(der))
+            .map(PrivateKey)
+            .map_err(invalid_private_key)
+    }
+
+    /// Loads a key from PKCS#8 or PKCS#1 PEM data.
+    pub fn from_pem(pem: &str) -> Result<Self> {
+        let (_, der) = pem::decode_vec(pem.as_bytes()).map_err(invalid_private_key)?;
+        Self::from_der(&der)
+    }
+
+    /// Loads a key from a PEM or DER file.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let data = fs::read(path)?;
+        match std::str::from_utf8(&data) {
+            Ok(text) if text.trim_start().starts_with("-----BEGIN") => Self::from_pem(text),
+            _ => Self::from_der(&data),
+        }
+    }
+
+    /// Returns the public half of this key.
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(self.0.to_public_key())
+    }
+
+    /// The length in bytes of signatures created by this key.
+    fn signature_len(&self) -> usize {
+        self.0.size()
+    }
+
+    fn sign(&self, algorithm: SignatureAlgorithm, digests: &Digests) -> Result<Vec<u8>> {
+        let result = match algorithm {
+            SignatureAlgorithm::RsaPkcs1Sha1 => self.0.sign(
+                Pkcs1v15Sign::new::<Sha1>(),
+                digests.sha1.as_deref().unwrap_or_default(),
+            ),
+            SignatureAlgorithm::RsaPkcs1Sha384 => self.0.sign(
+                Pkcs1v15Sign::new::<Sha384>(),
+                digests.sha384.as_deref().unwrap_or_default(),
+            ),
+        };
+
+        result.map_err(|e| MarError::InvalidKey(format!("Signing failed: {}", e)))
+    }
+}
+
+impl From<RsaPrivateKey> for PrivateKey {
+    fn from(key: RsaPrivateKey) -> Self {
+        PrivateKey(key)
+    }
+}
+
+/// Digests of the signed data of a MAR file.
+#[derive(Default)]
+struct Digests {
+    sha1: Option<Vec<u8>>,
+    sha384: Option<Vec<u8>>,
+}
+
+/// Computes digests of the signed data of a MAR file as it is written.
+#[derive(Default)]
+pub(crate) struct Hasher {
+    sha1: Option<Sha1>,
+    sha384: Option<Sha384>,
+}
+
+impl Hasher {
+    /// Creates a hasher for the given algorithms.
+    pub(crate) fn new<I>(algorithms: I) -> Self
+    where
+        I: IntoIterator<Item = SignatureAlgorithm>,
+    {
+        let mut hasher = Hasher::default();
+        for algorithm in algorithms {
+            match algorithm {
+                SignatureAlgorithm::RsaPkcs1Sha1 => hasher.sha1 = Some(Sha1::new()),
+                SignatureAlgorithm::RsaPkcs1Sha384 => hasher.sha384 = Some(Sha384::new()),
+            }
+        }
+        hasher
+    }
+
+    fn finish(self) -> Digests {
+        Digests {
+            sha1: self.sha1.map(|h| h.finalize().to_vec()),
+            sha384: self.sha384.map(|h| h.finalize().to_vec()),
+        }
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(ref mut sha1) = self.sha1 {
+            sha1.update(buf);
+        }
+        if let Some(ref mut sha384) = self.sha384 {
+            sha384.update(buf);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Verifies the signatures of a MAR file.
+///
+/// Every key must successfully verify at least one of the signatures in the file, and at least
+/// one key must be given. The signed data is the entire file except for the signatures
+/// themselves, as in Firefox's `mar_verify.c`.
+pub fn verify<R>(mut archive: R, keys: &[PublicKey]) -> Result<()>
+where
+    R: Read + Seek,
+{
+    let info = get_info(&mut archive)?;
+    verify_with_info(archive, &info, keys)
+}
+
+/// Verifies the signatures of a MAR file given its metadata.
+pub(crate) fn verify_with_info<R>(
+    mut archive: R,
+    info: &MarFileInfo,
+    keys: &[PublicKey],
+) -> Result<()>
+where
+    R: Read + Seek,
+{
+    if keys.is_empty() {
+        return Err(MarError::NoKeys);
+    }
+
+    if !info.has_signature_block || info.num_signatures == 0 {
+        return Err(MarError::Unsigned);
+    }
+
+    // Read the signatures so we know which digests to compute.
+    archive.seek(io::SeekFrom::Start(SIGNATURE_BLOCK_OFFSET + 4))?;
+    let mut signatures = Vec::with_capacity(info.num_signatures as usize);
+    for _ in 0..info.num_signatures {
+        signatures.push(read_signature(&mut archive)?);
+    }
+    let mut hasher = Hasher::new(signatures.iter().filter_map(Signature::algorithm));
+
+    // Hash the header and signature count.
+    archive.rewind()?;
+    io::copy(
+        &mut archive.by_ref().take(SIGNATURE_BLOCK_OFFSET + 4),
+        &mut hasher,
+    )?;
+
+    // Hash the algorithm and length of each signature, but not the signature itself.
+    for signature in &signatures {
+        io::copy(&mut archive.by_ref().take(8), &mut hasher)?;
+        archive.seek(io::SeekFrom::Current(signature.data.len() as i64))?;
+    }
+
+    // Hash the rest of the file.
+    io::copy(&mut archive, &mut hasher)?;
+    let digests = hasher.finish();
+
+    for key in keys {
+        if !signatures
+            .iter()
+            .any(|signature| key.verify(signature, &digests))
+        {
+            return Err(MarError::SignatureInvalid);
+        }
+    }
+
+    Ok(())
+}
+
+/// Signs a MAR file, writing the signed archive to `output`.
+///
+/// Any existing signatures in the input are replaced by one signature for each of the given keys.
+/// The input may be any MAR file, including old-style files without a signature block. Use
+/// `strip_signatures` to remove the signatures instead.
+pub fn sign<R, W>(input: R, mut output: W, keys: &[(PrivateKey, SignatureAlgorithm)]) -> Result<()>
+where
+    R: Read + Seek,
+    W: Write + Seek,
+{
+    if keys.is_empty() {
+        return Err(MarError::NoKeys);
+    }
+
+    let slots: Vec<(u32, u32)> = keys
+        .iter()
+        .map(|(key, algorithm)| (algorithm.id(), key.signature_len() as u32))
+        .collect();
+    let mut hasher = Hasher::new(keys.iter().map(|(_, algorithm)| *algorithm));
+
+    let positions = repackage(input, &mut output, &slots, None, &mut hasher)?;
+    let digests = hasher.finish();
+
+    let end = output.stream_position()?;
+    for ((key, algorithm), position) in keys.iter().zip(positions) {
+        let signature = key.sign(*algorithm, &digests)?;
+        output.seek(SeekFrom::Start(position))?;
+        output.write_all(&signature)?;
+    }
+    output.seek(SeekFrom::Start(end))?;
+    Ok(output.flush()?)
+}
+
+/// Copies a MAR file to `output` without any signatures.
+pub fn strip_signatures<R, W>(input: R, output: W) -> Result<()>
+where
+    R: Read + Seek,
+    W: Write + Seek,
+{
+    repackage(input, output, &[], None, io::sink())?;
+    Ok(())
+}
+
+/// Copies a MAR file to `output`, replacing the data of the signature at `index`.
+///
+/// The new signature must be the same length as the one it replaces. This allows signatures
+/// created elsewhere to be imported into an archive that was signed with placeholder keys.
+pub fn import_signature<R, W>(
+    mut input: R,
+    mut output: W,
+    index: usize,
+    signature: &[u8],
+) -> Result<()>
+where
+    R: Read + Seek,
+    W: Write + Seek,
+{
+    let info = get_info(&mut input)?;
+    if index >= info.num_signatures as usize {
+        return Err(MarError::InvalidInput(format!(
+            "MAR file has no signature at index {}",
+            index
+        )));
+    }
+
+    // Find the position of the signature to replace.
+    input.seek(SeekFrom::Start(SIGNATURE_BLOCK_OFFSET + 4))?;
+    for _ in 0..index {
+        read_signature(&mut input)?;
+    }
+    let existing = read_signature(&mut input)?;
+    if existing.data.len() != signature.len() {
+        return Err(MarError::InvalidInput(format!(
+            "Signature is {} bytes but the existing signature is {} bytes",
+            signature.len(),
+            existing.data.len()
+        )));
+    }
+    let position = input.stream_position()? - signature.len() as u64;
+
+    // Copy the archive and overwrite the signature.
+    input.rewind()?;
+    let start = output.stream_position()?;
+    io::copy(&mut input, &mut output)?;
+    let end = output.stream_position()?;
+    output.seek(SeekFrom::Start(start + position))?;
+    output.write_all(signature)?;
+    output.seek(SeekFrom::Start(end))?;
+    Ok(output.flush()?)
+}
+
+/// Copies a MAR file to `output` with a new signature block and optionally new additional blocks.
+///
+/// The signature block contains a zero-filled signature for each `(algorithm_id, length)` slot.
+/// If `blocks` is `None` the existing additional blocks are copied unchanged, otherwise any
+/// replacement block equal to an existing one is copied from its original bytes. The offsets in
+/// the header and index are shifted to account for any change in the size of the replaced
+/// blocks. All of the signed data is written to `hasher`. Returns the position in `output` of
+/// the data of each signature.
+pub(crate) fn repackage<R, W, H>(
+    mut input: R,
+    mut output: W,
+    slots: &[(u32, u32)],
+    blocks: Option<&[AdditionalBlock]>,
+    mut hasher: H,
+) -> Result<Vec<u64>>
+where
+    R: Read + Seek,
+    W: Write + Seek,
+    H: Write,
+{
+    if slots.len() > MAX_SIGNATURES as usize {
+        return Err(MarError::InvalidInput(format!(
+            "A MAR file can have at most {} signatures",
+            MAX_SIGNATURES
+        )));
+    }
+    if let Some((_, length)) = slots
+        .iter()
+        .find(|(_, length)| *length > MAX_SIGNATURE_LENGTH)
+    {
+        return Err(MarError::InvalidInput(format!(
+            "Signature is {} bytes but a MAR file can hold at most {}",
+            length, MAX_SIGNATURE_LENGTH
+        )));
+    }
+
+    // Find where the data to be copied starts in the input.
+    let info = get_info(&mut input)?;
+    let mut old_content_start = if info.has_signature_block {
+        read_signatures(&mut input)?;
+        input.stream_position()?
+    } else {
+        MAR_ID_SIZE as u64 + 4
+    };
+
+    // Serialize the replacement additional blocks, the old ones are then skipped. Blocks that
+    // are unchanged keep their original bytes, which may include padding or trailing data that
+    // would be lost by writing them out again.
+    let mut new_blocks = Vec::new();
+    if let Some(blocks) = blocks {
+        let mut originals = Vec::new();
+        if info.has_additional_blocks {
+            input.seek(SeekFrom::Start(info.offset_additional_blocks as u64))?;
+            for _ in 0..info.num_additional_blocks {
+                let size = input.read_u32::<BigEndian>().map_err(truncated)?;
+                if size < 8 {
+                    return Err(MarError::Malformed(
+                        "Additional block is too small".to_owned(),
+                    ));
+                }
+                let mut raw = vec![0; size as usize];
+                (&mut raw[0..4]).write_u32::<BigEndian>(size)?;
+                input.read_exact(&mut raw[4..]).map_err(truncated)?;
+                let id = (&raw[4..8]).read_u32::<BigEndian>()?;
+                let parsed = parse_additional_block(id, raw[8..].to_vec()).ok();
+                originals.push((parsed, raw));
+            }
+            old_content_start = input.stream_position()?;
+        }
+
+        new_blocks.write_u32::<BigEndian>(blocks.len() as u32)?;
+        for block in blocks {
+            match originals
+                .iter_mut()
+                .find(|(parsed, _)| parsed.as_ref() == Some(block))
+            {
+                Some((parsed, raw)) => {
+                    new_blocks.write_all(raw)?;
+                    *parsed = None;
+                }
+                None => write_additional_block(&mut new_blocks, block)?,
+            }
+        }
+    }
+
+    // Read the index.
+    input.seek(SeekFrom::Start(info.offset_to_index as u64))?;
+    let size_of_index = input.read_u32::<BigEndian>().map_err(truncated)?;
+    let mut index = vec![0; size_of_index as usize];
+    input.read_exact(&mut index).map_err(truncated)?;
+
+    // Work out the new layout.
+    let new_content_start = SIGNATURE_BLOCK_OFFSET
+        + 4
+        + slots.iter().map(|(_, len)| 8 + *len as u64).sum::<u64>()
+        + new_blocks.len() as u64;
+    let shift = new_content_start as i64 - old_content_start as i64;
+    let offset_to_index = shift_offset(info.offset_to_index, shift)?;
+    shift_index(&mut index, shift)?;
+    let file_size = offset_to_index as u64 + 4 + index.len() as u64;
+
+    let start = output.stream_position()?;
+    let mut signed = Tee(&mut output, &mut hasher);
+
+    // Write the header.
+    signed.write_all(MAR_ID)?;
+    signed.write_u32::<BigEndian>(offset_to_index)?;
+    signed.write_u64::<BigEndian>(file_size)?;
+
+    // Write the signature block, leaving space for the signatures.
+    signed.write_u32::<BigEndian>(slots.len() as u32)?;
+    let mut positions = Vec::with_capacity(slots.len());
+    for (algorithm_id, len) in slots {
+        signed.write_u32::<BigEndian>(*algorithm_id)?;
+        signed.write_u32::<BigEndian>(*len)?;
+        positions.push(signed.0.stream_position()?);
+        signed.0.write_all(&vec![0; *len as usize])?;
+    }
+
+    signed.write_all(&new_blocks)?;
+
+    // Copy the content, including the additional blocks if they weren't replaced.
+    input.seek(SeekFrom::Start(old_content_start))?;
+    let content_len = (info.offset_to_index as u64)
+        .checked_sub(old_content_start)
+        .ok_or_else(|| MarError::Malformed("Index overlaps the signature block".to_owned()))?;
+    let copied = io::copy(&mut input.by_ref().take(content_len), &mut signed)?;
+    if copied != content_len {
+        return Err(MarError::Malformed("Unexpected end of file".to_owned()));
+    }
+
+    // Write the index.
+    signed.write_u32::<BigEndian>(size_of_index)?;
+    signed.write_all(&index)?;
+
+    debug_assert_eq!(output.stream_position()? - start, file_size);
+    Ok(positions)
+}
+
+/// Shifts the offsets of every entry in a raw index.
+fn shift_index(mut index: &mut [u8], shift: i64) -> Result<()> {
+    while !index.is_empty() {
+        if index.len() < 12 {
+            return Err(MarError::Malformed(
+                "Index ends with a partial entry".to_owned(),
+            ));
+        }
+        let offset = (&index[0..4]).read_u32::<BigEndian>()?;
+        (&mut index[0..4]).write_u32::<BigEndian>(shift_offset(offset, shift)?)?;
+
+        let name_len = index[12..].iter().position(|b| *b == 0).ok_or_else(|| {
+            MarError::Malformed("Index ends with an unterminated name".to_owned())
+        })?;
+        index = &mut index[12 + name_len + 1..];
+    }
+    Ok(())
+}
+
+fn shift_offset(offset: u32, shift: i64) -> Result<u32> {
+    u32::try_from(offset as i64 + shift)
+        .map_err(|_| MarError::Malformed("MAR file size overflow".to_owned()))
+}
+
+/// Writes to two writers at once.
+struct Tee<A, B>(A, B);
+
+impl<A: Write, B: Write> Write for Tee<A, B> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.0.write(buf)?;
+        self.1.write_all(&buf[..written])?;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()?;
+        self.1.flush()
+    }
+}
+
+fn invalid_public_key<E: ToString>(error: E) -> MarError {
+    MarError::InvalidKey(format!("Not a valid public key: {}", error.to_string()))
+}
+
+fn invalid_private_key<E: ToString>(error: E) -> MarError {
+    MarError::InvalidKey(format!("Not a valid private key: {}", error.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::OnceLock;
+
+    use rsa::pkcs1::{EncodeRsaPrivateKey, EncodeRsaPublicKey};
+    use rsa::pkcs8::{EncodePrivateKey, EncodePublicKey, LineEnding};
+    use rsa::rand_core::OsRng;
+
+    use super::*;
+    use crate::testing::{self, build, read_entry};
+    use crate::Mar;
+
+    fn unsigned_mar() -> Vec<u8> {
+        let mut builder = testing::builder();
+        builder.add_entry("a.txt", 0o644, &b"hello"[..]);
+        build(builder)
+    }
+
+    fn sign_mar(input: &[u8], keys: &[(PrivateKey, SignatureAlgorithm)]) -> Vec<u8> {
+        let mut signed = Cursor::new(Vec::new());
+        sign(Cursor::new(input), &mut signed, keys).unwrap();
+        signed.into_inner()
+    }
+
+    fn signed_mar(key: &PrivateKey) -> Vec<u8> {
+        sign_mar(
+            &unsigned_mar(),
+            &[(key.clone(), SignatureAlgorithm::RsaPkcs1Sha384)],
+        )
+    }
+
+    /// Returns one of two keys, generated once as key generation is slow.
+    fn private_key(n: usize) -> PrivateKey {
+        static KEYS: OnceLock<Vec<PrivateKey>> = OnceLock::new();
+        KEYS.get_or_init(|| {
+            (0..2)
+                .map(|_| RsaPrivateKey::new(&mut OsRng, 1024).unwrap().into())
+                .collect()
+        })[n]
+            .clone()
+    }
+
+    fn verify_mar(data: &[u8], keys: &[PublicKey]) -> Result<()> {
+        Mar::from_buffer(Cursor::new(data))?.verify(keys)
+    }
+
+    #[test]
+    fn verify_with_matching_key() {
+        let key = private_key(0);
+        verify_mar(&signed_mar(&key), &[key.public_key()]).unwrap();
+    }
+
+    #[test]
+    fn verify_with_other_key() {
+        assert!(matches!(
+            verify_mar(&signed_mar(&private_key(0)), &[private_key(1).public_key()]),
+            Err(MarError::SignatureInvalid)
+        ));
+    }
+
+    #[test]
+    fn verify_without_keys() {
+        assert!(matches!(
+            verify_mar(&signed_mar(&private_key(0)), &[]),
+            Err(MarError::NoKeys)
+        ));
+    }
+
+    #[test]
+    fn sign_without_keys() {
+        let result = sign(Cursor::new(unsigned_mar()), Cursor::new(Vec::new()), &[]);
+        assert!(matches!(result, Err(MarError::NoKeys)));
+    }
+
+    #[test]
+    fn sign_with_several_keys() {
+        let (sha1_key, sha384_key) = (private_key(0), private_key(1));
+        let signed = sign_mar(
+            &unsigned_mar(),
+            &[
+                (sha1_key.clone(), SignatureAlgorithm::RsaPkcs1Sha1),
+                (sha384_key.clone(), SignatureAlgorithm::RsaPkcs1Sha384),
+            ],
+        );
+
+        let mut mar = Mar::from_buffer(Cursor::new(&signed)).unwrap();
+        let signatures = mar.signatures().unwrap();
+        let algorithms = signatures.iter().map(Signature::algorithm);
+        assert_eq!(
+            algorithms.collect::<Vec<_>>(),
+            [
+                Some(SignatureAlgorithm::RsaPkcs1Sha1),
+                Some(SignatureAlgorithm::RsaPkcs1Sha384)
+            ]
+        );
+        assert!(signatures
+            .iter()
+            .all(|signature| signature.data.len() == 128));
+        assert_eq!(read_entry(&mut mar, "a.txt"), b"hello");
+
+        verify_mar(&signed, &[sha1_key.public_key()]).unwrap();
+        verify_mar(&signed, &[sha384_key.public_key()]).unwrap();
+        verify_mar(&signed, &[sha1_key.public_key(), sha384_key.public_key()]).unwrap();
+    }
+
+    #[test]
+    fn resign_signed_archive() {
+        let signed = signed_mar(&private_key(0));
+        let resigned = sign_mar(
+            &signed,
+            &[(private_key(1), SignatureAlgorithm::RsaPkcs1Sha1)],
+        );
+
+        let mut mar = Mar::from_buffer(Cursor::new(&resigned)).unwrap();
+        assert_eq!(mar.signatures().unwrap().len(), 1);
+        assert_eq!(mar.product_info().unwrap(), Some(testing::product_info()));
+        assert_eq!(read_entry(&mut mar, "a.txt"), b"hello");
+        assert!(mar.validate().unwrap().is_empty());
+
+        verify_mar(&resigned, &[private_key(1).public_key()]).unwrap();
+        assert!(matches!(
+            verify_mar(&resigned, &[private_key(0).public_key()]),
+            Err(MarError::SignatureInvalid)
+        ));
+    }
+
+    #[test]
+    fn strip_signed_archive() {
+        let mut stripped = Cursor::new(Vec::new());
+        strip_signatures(Cursor::new(signed_mar(&private_key(0))), &mut stripped).unwrap();
+
+        let stripped = stripped.into_inner();
+        let mut mar = Mar::from_buffer(Cursor::new(&stripped)).unwrap();
+        assert!(mar.signatures().unwrap().is_empty());
+        assert_eq!(read_entry(&mut mar, "a.txt"), b"hello");
+        assert_eq!(stripped.len(), unsigned_mar().len());
+        assert!(matches!(
+            verify_mar(&stripped, &[private_key(0).public_key()]),
+            Err(MarError::Unsigned)
+        ));
+    }
+
+    #[test]
+    fn import_external_signature() {
+        // The signed data is the same whichever key the slot was made for.
+        let placeholder = signed_mar(&private_key(0));
+        let external = signed_mar(&private_key(1));
+        let signature = read_signatures(Cursor::new(&external)).unwrap().remove(0);
+
+        let mut imported = Cursor::new(Vec::new());
+        import_signature(Cursor::new(&placeholder), &mut imported, 0, &signature.data).unwrap();
+        verify_mar(imported.get_ref(), &[private_key(1).public_key()]).unwrap();
+
+        let result = import_signature(
+            Cursor::new(&placeholder),
+            Cursor::new(Vec::new()),
+            0,
+            &signature.data[1..],
+        );
+        assert!(matches!(result, Err(MarError::InvalidInput(_))));
+        let result = import_signature(
+            Cursor::new(&placeholder),
+            Cursor::new(Vec::new()),
+            1,
+            &signature.data,
+        );
+        assert!(matches!(result, Err(MarError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn oversized_signature_slot() {
+        let slots = [(2, MAX_SIGNATURE_LENGTH + 1)];
+        let result = repackage(
+            Cursor::new(unsigned_mar()),
+            Cursor::new(Vec::new()),
+            &slots,
+            None,
+            io::sink(),
+        );
+        assert!(matches!(result, Err(MarError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn load_keys() {
+        let key = private_key(0);
+        let signed = signed_mar(&key);
+        let public = key.0.to_public_key();
+
+        let private_keys = [
+            PrivateKey::from_der(key.0.to_pkcs8_der().unwrap().as_bytes()).unwrap(),
+            PrivateKey::from_der(key.0.to_pkcs1_der().unwrap().as_bytes()).unwrap(),
+            PrivateKey::from_pem(&key.0.to_pkcs8_pem(LineEnding::LF).unwrap()).unwrap(),
+            PrivateKey::from_pem(&key.0.to_pkcs1_pem(LineEnding::LF).unwrap()).unwrap(),
+        ];
+        for loaded in private_keys {
+            assert_eq!(loaded.0, key.0);
+        }
+
+        let public_keys = [
+            PublicKey::from_der(public.to_public_key_der().unwrap().as_bytes()).unwrap(),
+            PublicKey::from_der(public.to_pkcs1_der().unwrap().as_bytes()).unwrap(),
+            PublicKey::from_pem(&public.to_public_key_pem(LineEnding::LF).unwrap()).unwrap(),
+            PublicKey::from_pem(&public.to_pkcs1_pem(LineEnding::LF).unwrap()).unwrap(),
+        ];
+        for loaded in public_keys {
+            verify_mar(&signed, &[loaded]).unwrap();
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let pem_path = dir.path().join("key.pem");
+        fs::write(&pem_path, key.0.to_pkcs8_pem(LineEnding::LF).unwrap()).unwrap();
+        assert_eq!(PrivateKey::from_path(&pem_path).unwrap().0, key.0);
+        let der_path = dir.path().join("key.der");
+        fs::write(&der_path, public.to_public_key_der().unwrap().as_bytes()).unwrap();
+        verify_mar(&signed, &[PublicKey::from_path(&der_path).unwrap()]).unwrap();
+
+        assert!(matches!(
+            PrivateKey::from_der(b"not a key"),
+            Err(MarError::InvalidKey(_))
+        ));
+        assert!(matches!(
+            PublicKey::from_pem("not a key"),
+            Err(MarError::InvalidKey(_))
+        ));
+    }
+}
@@ -0,0 +1,343 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Reading MAR files held entirely in memory without copying.
+//!
+//! `MarSlice` borrows from a byte slice, which may be a memory mapped file, and parses the index
+//! as it is iterated. Names and uncompressed entry data are returned as references into the
+//! slice so scanning many archives allocates very little.
+
+use std::borrow::Cow;
+use std::io::{Cursor, Read};
+use std::sync::OnceLock;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::compression::{compression_header, is_compressed, DecompressedRead};
+use crate::read::{
+    additional_blocks_with_info, check_index_bounds, info_from_offsets, parse_header,
+    read_product_info_with_info, read_signatures_with_info, AdditionalBlocks,
+};
+use crate::signing::{self, PublicKey, Signature};
+use crate::{validate, MarError, MarFileInfo, ProductInformation, Result};
+
+/// Size of the fixed fields of an index entry: offset, length and flags.
+const INDEX_ENTRY_SIZE: usize = 12;
+
+/// An entry in the index of a `MarSlice`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MarSliceItem<'a> {
+    /// Position of the item within the archive.
+    pub offset: u32,
+    /// Length of data in bytes.
+    pub length: u32,
+    /// File mode bits.
+    pub flags: u32,
+    /// File path.
+    ///
+    /// This is borrowed from the mar unless the name is not valid UTF-8, in which case it is a
+    /// lossy conversion suitable for display.
+    pub name: Cow<'a, str>,
+    /// The name exactly as it is stored in the index.
+    pub name_bytes: &'a [u8],
+}
+
+impl MarSliceItem<'_> {
+    /// Returns true if the stored name is valid UTF-8, and so `name` is exact.
+    pub fn is_utf8_name(&self) -> bool {
+        matches!(self.name, Cow::Borrowed(_))
+    }
+}
+
+/// A high level interface to read the contents of a mar file held in memory.
+#[derive(Clone, Debug)]
+pub struct MarSlice<'a> {
+    data: &'a [u8],
+    info: MarFileInfo,
+    index: &'a [u8],
+    /// The entries ordered by name, built the first time an entry is looked up.
+    sorted: OnceLock<SortedEntries<'a>>,
+}
+
+/// The entries of a `MarSlice` ordered by name.
+#[derive(Clone, Debug)]
+struct SortedEntries<'a> {
+    items: Vec<MarSliceItem<'a>>,
+    /// False if a malformed entry stopped the index from being read in full.
+    complete: bool,
+}
+
+impl<'a> SortedEntries<'a> {
+    fn new(files: SliceFiles<'a>) -> Self {
+        let mut items = Vec::new();
+        let mut complete = true;
+        for item in files {
+            match item {
+                Ok(item) => items.push(item),
+                Err(_) => complete = false,
+            }
+        }
+        // The sort is stable so the first of several entries with the same name comes first.
+        items.sort_by(|a, b| a.name_bytes.cmp(b.name_bytes));
+
+        SortedEntries { items, complete }
+    }
+}
+
+impl<'a> MarSlice<'a> {
+    /// Creates a MarSlice instance from the bytes of a mar file.
+    pub fn new(data: &'a [u8]) -> Result<MarSlice<'a>> {
+        let offset_to_index = parse_header(data)?;
+        let index = index_bytes(data, offset_to_index)?;
+
+        // An empty index means the content (of which there is none) ends where the index starts.
+        let offset_to_content = if index.len() >= 4 {
+            BigEndian::read_u32(index)
+        } else {
+            offset_to_index
+        };
+        let info = info_from_offsets(Cursor::new(data), offset_to_index, offset_to_content)?;
+
+        Ok(MarSlice {
+            data,
+            info,
+            index,
+            sorted: OnceLock::new(),
+        })
+    }
+
+    /// Returns the metadata about this mar.
+    pub fn info(&self) -> &MarFileInfo {
+        &self.info
+    }
+
+    /// Returns the bytes of the whole mar file.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Returns an Iterator over the entries in this mar, in the order they appear in the index.
+    pub fn files(&self) -> SliceFiles<'a> {
+        SliceFiles { index: self.index }
+    }
+
+    /// Returns the first entry with the given name, if there is one.
+    ///
+    /// The whole index is read and sorted the first time this is called. If the entry is not
+    /// found and the index is malformed the reason is returned instead. Entries whose names are
+    /// not UTF-8 never match, even by their lossy name.
+    pub fn entry(&self, name: &str) -> Result<Option<MarSliceItem<'a>>> {
+        let name = name.as_bytes();
+        let sorted = self.sorted.get_or_init(|| SortedEntries::new(self.files()));
+        let position = sorted.items.partition_point(|item| item.name_bytes < name);
+        match sorted.items.get(position) {
+            Some(item) if item.name_bytes == name => Ok(Some(item.clone())),
+            _ if sorted.complete => Ok(None),
+            // Find the entry that stopped the index from being read.
+            _ => self.files().find_map(Result::err).map_or(Ok(None), Err),
+        }
+    }
+
+    /// Returns the stored bytes of an entry without decompressing them.
+    pub fn read_raw(&self, item: &MarSliceItem) -> Result<&'a [u8]> {
+        // Check in u64 so hostile offsets can't overflow a 32-bit usize.
+        let end = item.offset as u64 + item.length as u64;
+        if end > self.info.offset_to_index as u64 {
+            return Err(MarError::EntryOverlapsIndex {
+                name: item.name.clone().into_owned(),
+            });
+        }
+        Ok(&self.data[item.offset as usize..end as usize])
+    }
+
+    /// Reads the contents of an entry, decompressing it if necessary.
+    pub fn read(&self, item: &MarSliceItem) -> Result<DecompressedRead<&'a [u8]>> {
+        let raw = self.read_raw(item)?;
+        Ok(DecompressedRead::new(&compression_header(raw), raw))
+    }
+
+    /// Returns the contents of an entry, borrowed from the mar if the entry is not compressed.
+    pub fn contents(&self, item: &MarSliceItem) -> Result<Cow<'a, [u8]>> {
+        let raw = self.read_raw(item)?;
+        if !is_compressed(raw) {
+            return Ok(Cow::Borrowed(raw));
+        }
+
+        let mut data = Vec::new();
+        self.read(item)?.read_to_end(&mut data)?;
+        Ok(Cow::Owned(data))
+    }
+
+    /// Returns the product information from this mar, if it has any.
+    pub fn product_info(&self) -> Result<Option<ProductInformation>> {
+        read_product_info_with_info(Cursor::new(self.data), &self.info)
+    }
+
+    /// Returns an Iterator over the additional blocks in this mar.
+    pub fn additional_blocks(&self) -> Result<AdditionalBlocks<Cursor<&'a [u8]>>> {
+        Ok(additional_blocks_with_info(
+            Cursor::new(self.data),
+            &self.info,
+        ))
+    }
+
+    /// Returns the signatures in this mar.
+    pub fn signatures(&self) -> Result<Vec<Signature>> {
+        read_signatures_with_info(Cursor::new(self.data), &self.info)
+    }
+
+    /// Verifies that every one of the given keys matches a signature in this mar.
+    pub fn verify(&self, keys: &[PublicKey]) -> Result<()> {
+        signing::verify_with_info(Cursor::new(self.data), &self.info, keys)
+    }
+
+    /// Checks the structure of this mar as strictly as Firefox does, returning every problem
+    /// found.
+    pub fn validate(&self) -> Result<Vec<MarError>> {
+        validate::validate(Cursor::new(self.data), &self.info)
+    }
+}
+
+/// Returns the bytes of the index, checking that it lies within the file.
+fn index_bytes(data: &[u8], offset_to_index: u32) -> Result<&[u8]> {
+    let file_size = data.len() as u64;
+    check_index_bounds(offset_to_index, 0, file_size)?;
+
+    // Both ends lie within the file once checked, so they fit in a usize.
+    let start = offset_to_index as u64 + 4;
+    let size_of_index = BigEndian::read_u32(&data[offset_to_index as usize..]);
+    check_index_bounds(offset_to_index, size_of_index, file_size)?;
+    let end = start + size_of_index as u64;
+    Ok(&data[start as usize..end as usize])
+}
+
+/// An iterator over the entries of a `MarSlice`.
+#[derive(Clone, Debug)]
+pub struct SliceFiles<'a> {
+    index: &'a [u8],
+}
+
+impl<'a> SliceFiles<'a> {
+    fn read_item(&mut self) -> Result<MarSliceItem<'a>> {
+        let index = self.index;
+        if index.len() < INDEX_ENTRY_SIZE {
+            return Err(MarError::Malformed(
+                "Index ends with a partial entry".to_owned(),
+            ));
+        }
+
+        let rest = &index[INDEX_ENTRY_SIZE..];
+        let name_length = rest.iter().position(|b| *b == 0).ok_or_else(|| {
+            MarError::Malformed("Index ends with an unterminated name".to_owned())
+        })?;
+        self.index = &rest[name_length + 1..];
+        let name_bytes = &rest[..name_length];
+
+        Ok(MarSliceItem {
+            offset: BigEndian::read_u32(&index[0..]),
+            length: BigEndian::read_u32(&index[4..]),
+            flags: BigEndian::read_u32(&index[8..]),
+            name: String::from_utf8_lossy(name_bytes),
+            name_bytes,
+        })
+    }
+}
+
+impl<'a> Iterator for SliceFiles<'a> {
+    type Item = Result<MarSliceItem<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index.is_empty() {
+            return None;
+        }
+
+        let result = self.read_item();
+        // Stop after a malformed entry since the start of the next one is unknown.
+        if result.is_err() {
+            self.index = &[];
+        }
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, build_with_signature_slot, lossy_collision, product_info};
+
+    fn archive() -> Vec<u8> {
+        let mut builder = testing::builder();
+        builder.add_entry("b.txt", 0o644, &b"first"[..]);
+        builder.add_entry("a.txt", 0o644, &b"hello"[..]);
+        builder.add_entry("b.txt", 0o644, &b"second"[..]);
+        build_with_signature_slot(builder)
+    }
+
+    #[test]
+    fn entry_lookup() {
+        let data = archive();
+        let mar = MarSlice::new(&data).unwrap();
+
+        let item = mar.entry("a.txt").unwrap().unwrap();
+        assert_eq!(mar.contents(&item).unwrap(), &b"hello"[..]);
+        let item = mar.entry("b.txt").unwrap().unwrap();
+        assert_eq!(mar.contents(&item).unwrap(), &b"first"[..]);
+        assert!(mar.entry("c.txt").unwrap().is_none());
+    }
+
+    #[test]
+    fn entry_lookup_in_truncated_index() {
+        let mut data = archive();
+        // Overwrite the end of the last name, including its terminator.
+        let len = data.len();
+        data[len - 4..].fill(1);
+        let mar = MarSlice::new(&data).unwrap();
+
+        assert!(mar.entry("a.txt").unwrap().is_some());
+        assert!(matches!(mar.entry("c.txt"), Err(MarError::Malformed(_))));
+    }
+
+    #[test]
+    fn entry_out_of_bounds() {
+        let mut data = archive();
+        // Point a.txt at the very end of the address space.
+        let name = data.windows(6).rposition(|w| w == b"a.txt\0").unwrap();
+        data[name - 12..name - 4].fill(0xff);
+        let mar = MarSlice::new(&data).unwrap();
+
+        let item = mar.entry("a.txt").unwrap().unwrap();
+        assert!(matches!(
+            mar.read_raw(&item),
+            Err(MarError::EntryOverlapsIndex { name }) if name == "a.txt"
+        ));
+    }
+
+    #[test]
+    fn non_utf8_names() {
+        let data = lossy_collision();
+        let mar = MarSlice::new(&data).unwrap();
+        let items = mar.files().collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].name, "a\u{fffd}.txt");
+        assert_eq!(items[0].name_bytes, b"a\xe2\x82.txt");
+        assert!(!items[0].is_utf8_name());
+        assert!(items[1].is_utf8_name());
+
+        let item = mar.entry("a\u{fffd}.txt").unwrap().unwrap();
+        assert_eq!(mar.contents(&item).unwrap(), &b"utf-8"[..]);
+    }
+
+    #[test]
+    fn metadata() {
+        let data = archive();
+        let mar = MarSlice::new(&data).unwrap();
+
+        assert_eq!(mar.product_info().unwrap(), Some(product_info()));
+        assert_eq!(mar.additional_blocks().unwrap().count(), 1);
+        let signatures = mar.signatures().unwrap();
+        assert_eq!(signatures.len(), 1);
+        assert_eq!(signatures[0].algorithm_id, 2);
+        assert_eq!(signatures[0].data.len(), 512);
+    }
+}
@@ -0,0 +1,240 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Reading MAR files from sources that cannot seek, such as pipes or network responses.
+//!
+//! The index of a MAR file comes after the content it describes, so nothing can be extracted
+//! until the whole file has been read. The file is read once into a `Spool`, held in memory up to
+//! a limit and in an anonymous temporary file beyond that, and then read as any other `Mar`.
+
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+
+use crate::error::truncated;
+use crate::read::{ReadOptions, MAR_ID, MAR_ID_SIZE};
+use crate::{Mar, MarError, Result};
+
+/// The default amount of data held in memory before spilling to a temporary file.
+const DEFAULT_MEMORY_LIMIT: u64 = 32 * 1024 * 1024;
+
+/// Options controlling how a MAR file is read from a stream.
+#[derive(Clone, Debug)]
+pub struct StreamOptions {
+    memory_limit: u64,
+    read_options: ReadOptions,
+}
+
+impl Default for StreamOptions {
+    fn default() -> Self {
+        StreamOptions {
+            memory_limit: DEFAULT_MEMORY_LIMIT,
+            read_options: ReadOptions::new(),
+        }
+    }
+}
+
+impl StreamOptions {
+    /// Creates the default options, which hold up to 32MiB in memory and read the index with the
+    /// default `ReadOptions`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets how many bytes to hold in memory before moving the data to a temporary file.
+    pub fn memory_limit(&mut self, memory_limit: u64) -> &mut Self {
+        self.memory_limit = memory_limit;
+        self
+    }
+
+    /// Sets the options used to read the index once the stream has been read.
+    pub fn read_options(&mut self, read_options: ReadOptions) -> &mut Self {
+        self.read_options = read_options;
+        self
+    }
+}
+
+enum Storage {
+    Memory(Cursor<Vec<u8>>),
+    File(File),
+}
+
+/// A MAR file read from a stream, held in memory or in a temporary file.
+///
+/// The temporary file is deleted when the spool is dropped.
+pub struct Spool {
+    storage: Storage,
+    memory_limit: u64,
+}
+
+impl Spool {
+    fn new(memory_limit: u64) -> Spool {
+        Spool {
+            storage: Storage::Memory(Cursor::new(Vec::new())),
+            memory_limit,
+        }
+    }
+
+    /// Moves the data to a temporary file, keeping the current position.
+    fn spill(&mut self) -> io::Result<()> {
+        if let Storage::Memory(cursor) = &self.storage {
+            let mut file = tempfile::tempfile()?;
+            file.write_all(cursor.get_ref())?;
+            file.seek(SeekFrom::Start(cursor.position()))?;
+            self.storage = Storage::File(file);
+        }
+        Ok(())
+    }
+}
+
+impl Read for Spool {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.storage {
+            Storage::Memory(cursor) => cursor.read(buf),
+            Storage::File(file) => file.read(buf),
+        }
+    }
+}
+
+impl Seek for Spool {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match &mut self.storage {
+            Storage::Memory(cursor) => cursor.seek(pos),
+            Storage::File(file) => file.seek(pos),
+        }
+    }
+}
+
+impl Write for Spool {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Storage::Memory(cursor) = &self.storage {
+            if cursor.get_ref().len() as u64 + buf.len() as u64 > self.memory_limit {
+                self.spill()?;
+            }
+        }
+
+        match &mut self.storage {
+            Storage::Memory(cursor) => cursor.write(buf),
+            Storage::File(file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.storage {
+            Storage::Memory(_) => Ok(()),
+            Storage::File(file) => file.flush(),
+        }
+    }
+}
+
+/// Reads a MAR file from a stream in a single pass.
+///
+/// The magic bytes are checked before anything is buffered, so a stream that is not a MAR file
+/// fails immediately. The returned `Mar` can be verified, validated and extracted as usual.
+pub fn read_stream<S: Read>(mut stream: S, options: &StreamOptions) -> Result<Mar<Spool>> {
+    let mut id = [0; MAR_ID_SIZE];
+    stream.read_exact(&mut id).map_err(truncated)?;
+    if id != *MAR_ID {
+        return Err(MarError::BadMagic);
+    }
+
+    let mut spool = Spool::new(options.memory_limit);
+    spool.write_all(&id)?;
+    io::copy(&mut stream, &mut spool)?;
+
+    Mar::from_buffer_with_options(spool, &options.read_options)
+}
+
+impl Mar<Spool> {
+    /// Creates a Mar instance by reading a stream that cannot seek, using the default options.
+    pub fn from_stream<S: Read>(stream: S) -> Result<Mar<Spool>> {
+        read_stream(stream, &StreamOptions::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rsa::rand_core::OsRng;
+    use rsa::RsaPrivateKey;
+
+    use super::*;
+    use crate::signing::{self, PrivateKey, SignatureAlgorithm};
+    use crate::testing::{self, build, lossy_collision, read_entry};
+
+    fn is_spilled(mar: &Mar<Spool>) -> bool {
+        matches!(mar.buffer.storage, Storage::File(_))
+    }
+
+    #[test]
+    fn spills_past_memory_limit() {
+        let content = "spilled ".repeat(1000).into_bytes();
+        let mut builder = testing::builder();
+        builder.add_entry("a.txt", 0o644, &content[..]);
+        let key = PrivateKey::from(RsaPrivateKey::new(&mut OsRng, 1024).unwrap());
+        let mut signed = Cursor::new(Vec::new());
+        signing::sign(
+            Cursor::new(build(builder)),
+            &mut signed,
+            &[(key.clone(), SignatureAlgorithm::RsaPkcs1Sha384)],
+        )
+        .unwrap();
+        let data = signed.into_inner();
+
+        let mut mar = Mar::from_stream(&data[..]).unwrap();
+        assert!(!is_spilled(&mar));
+
+        let mut spilled = read_stream(&data[..], StreamOptions::new().memory_limit(1024)).unwrap();
+        assert!(is_spilled(&spilled));
+
+        for mar in [&mut mar, &mut spilled] {
+            assert_eq!(read_entry(mar, "a.txt"), content);
+            assert_eq!(mar.product_info().unwrap(), Some(testing::product_info()));
+            mar.verify(&[key.public_key()]).unwrap();
+            assert!(mar.validate().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn spill_keeps_position() {
+        let mut spool = Spool::new(4);
+        spool.write_all(b"abc").unwrap();
+        assert!(matches!(spool.storage, Storage::Memory(_)));
+        spool.write_all(b"def").unwrap();
+        assert!(matches!(spool.storage, Storage::File(_)));
+        assert_eq!(spool.stream_position().unwrap(), 6);
+
+        let mut content = String::new();
+        spool.rewind().unwrap();
+        spool.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "abcdef");
+    }
+
+    #[test]
+    fn read_options() {
+        let data = lossy_collision();
+        assert!(matches!(
+            Mar::from_stream(&data[..]),
+            Err(MarError::InvalidUtf8Name { .. })
+        ));
+
+        let mut read_options = ReadOptions::new();
+        read_options.non_utf8_names(true);
+        let mut options = StreamOptions::new();
+        options.read_options(read_options);
+        let mar = read_stream(&data[..], &options).unwrap();
+        assert_eq!(mar.len(), 2);
+        assert!(!mar.files()[0].is_utf8_name());
+    }
+
+    #[test]
+    fn rejects_other_streams() {
+        assert!(matches!(
+            Mar::from_stream(&b"NOT A MAR FILE"[..]),
+            Err(MarError::BadMagic)
+        ));
+        assert!(matches!(
+            Mar::from_stream(&b"MA"[..]),
+            Err(MarError::Malformed(_))
+        ));
+    }
+}
@@ -0,0 +1,73 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Archives and helpers shared by the tests of several modules.
+
+use std::io::{self, Cursor, Read, Seek, Write};
+
+use bzip2::write::BzEncoder;
+
+use crate::signing;
+use crate::write::MarBuilder;
+use crate::{Mar, ProductInformation};
+
+/// The product information used by test archives.
+pub(crate) fn product_info() -> ProductInformation {
+    ProductInformation {
+        mar_channel_id: "release".to_owned(),
+        product_version: "100.0".to_owned(),
+    }
+}
+
+/// Returns a builder with the test product information.
+pub(crate) fn builder<'a>() -> MarBuilder<'a> {
+    let mut builder = MarBuilder::new();
+    builder.product_information(product_info());
+    builder
+}
+
+/// Builds an archive in memory.
+pub(crate) fn build(builder: MarBuilder<'_>) -> Vec<u8> {
+    let mut archive = Cursor::new(Vec::new());
+    builder.build(&mut archive).unwrap();
+    archive.into_inner()
+}
+
+/// Builds an archive in memory with an empty slot for a 512 byte RSA-PKCS1-SHA384 signature.
+pub(crate) fn build_with_signature_slot(builder: MarBuilder<'_>) -> Vec<u8> {
+    let unsigned = Cursor::new(build(builder));
+    let mut output = Cursor::new(Vec::new());
+    signing::repackage(unsigned, &mut output, &[(2, 512)], None, io::sink()).unwrap();
+    output.into_inner()
+}
+
+/// Compresses data with bzip2, as older MAR files were. The builder stores the result as is.
+pub(crate) fn bz2(data: &[u8]) -> Vec<u8> {
+    let mut encoder = BzEncoder::new(Vec::new(), bzip2::Compression::best());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// Builds an archive whose first name is not UTF-8 but is converted lossily to the second.
+pub(crate) fn lossy_collision() -> Vec<u8> {
+    let mut builder = MarBuilder::new();
+    builder.add_entry("a12.txt", 0o644, &b"raw"[..]);
+    builder.add_entry("a\u{fffd}.txt", 0o644, &b"utf-8"[..]);
+    let mut data = build(builder);
+
+    let position = data.windows(7).position(|w| w == b"a12.txt").unwrap();
+    data[position + 1..position + 3].copy_from_slice(b"\xe2\x82");
+    data
+}
+
+/// Reads the decompressed contents of the named entry.
+pub(crate) fn read_entry<R: Read + Seek>(mar: &mut Mar<R>, name: &str) -> Vec<u8> {
+    let mut content = Vec::new();
+    mar.read_by_name(name)
+        .unwrap()
+        .unwrap()
+        .read_to_end(&mut content)
+        .unwrap();
+    content
+}
@@ -0,0 +1,603 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Generating update MAR files, as Mozilla's `make_full_update.sh` and
+//! `make_incremental_update.sh` do.
+
+use std::fs;
+use std::io::{Cursor, ErrorKind, Read, Seek, Write};
+use std::path::Path;
+
+use crate::compression::{CompressedWrite, CompressionType};
+use crate::manifest::{Instruction, Manifest, UpdateType, MANIFEST_NAMES};
+use crate::patch::create_patch;
+use crate::write::{file_flags, MarBuilder, DEFAULT_FLAGS};
+use crate::{Mar, MarError, MarItem, ProductInformation, Result};
+
+/// Name of the manifest written to generated updates.
+const MANIFEST_NAME: &str = "updatev3.manifest";
+
+/// File listing everything in an installation, removed before applying a complete update.
+const PRECOMPLETE: &str = "precomplete";
+
+/// File listing what to remove from an installation when updating to a build.
+const REMOVED_FILES: &str = "removed-files";
+
+/// Directory containing distribution extensions, which are only updated if already installed.
+const EXTENSIONS_DIR: &str = "distribution/extensions/";
+
+/// Files that are only added if they don't already exist, so local changes are kept.
+const ADD_IF_NOT_FILES: &[&str] = &["channel-prefs.js", "update-settings.ini"];
+
+/// Options controlling how complete updates are built.
+#[derive(Clone, Debug)]
+pub struct FullUpdateOptions {
+    product_information: Option<ProductInformation>,
+    precomplete: bool,
+}
+
+impl Default for FullUpdateOptions {
+    fn default() -> Self {
+        Self {
+            product_information: None,
+            precomplete: true,
+        }
+    }
+}
+
+impl FullUpdateOptions {
+    /// Creates the default options, which generate a `precomplete` file and include no product
+    /// information.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the product information to include in the update.
+    pub fn product_information(&mut self, info: ProductInformation) -> &mut Self {
+        self.product_information = Some(info);
+        self
+    }
+
+    /// Sets whether to generate the `precomplete` file listing everything in the update, as
+    /// Mozilla's `createprecomplete.py` does. Otherwise any `precomplete` file in the directory is
+    /// included as is.
+    pub fn precomplete(&mut self, precomplete: bool) -> &mut Self {
+        self.precomplete = precomplete;
+        self
+    }
+}
+
+/// Builds a complete update containing every file in `dir`.
+///
+/// Files are compressed with XZ, using the BCJ filter for executables. Files within a
+/// distribution extension are only added if the extension is installed, and `channel-prefs.js`
+/// and `update-settings.ini` are only added if missing. Everything listed in the directory's
+/// `removed-files` is removed. Symlinks are ignored.
+pub fn build_full_update<P, W>(dir: P, output: W, options: &FullUpdateOptions) -> Result<()>
+where
+    P: AsRef<Path>,
+    W: Write + Seek,
+{
+    let dir = dir.as_ref();
+    let mut files = Vec::new();
+    let mut dirs = Vec::new();
+    list_dir(dir, "", &mut files, &mut dirs)?;
+    if options.precomplete && !files.iter().any(|name| name == PRECOMPLETE) {
+        files.push(PRECOMPLETE.to_owned());
+    }
+    // Mozilla's scripts list files in reverse order.
+    files.sort_by(|a, b| b.cmp(a));
+
+    let mut instructions: Vec<Instruction> = files
+        .iter()
+        .map(|name| {
+            if is_add_if_not(name) {
+                Instruction::AddIfNot {
+                    test: name.clone(),
+                    path: name.clone(),
+                }
+            } else {
+                add_instruction(name)
+            }
+        })
+        .collect();
+
+    match fs::read_to_string(dir.join(REMOVED_FILES)) {
+        Ok(text) => instructions.extend(removed_files_instructions(&text)),
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    let manifest = Manifest {
+        update_type: UpdateType::Complete,
+        instructions,
+    };
+    manifest.validate()?;
+
+    let mut builder = MarBuilder::new();
+    if let Some(info) = &options.product_information {
+        builder.product_information(info.clone());
+    }
+    builder.add_compressed_entry(
+        MANIFEST_NAME,
+        DEFAULT_FLAGS,
+        Cursor::new(manifest.to_string().into_bytes()),
+        CompressionType::Xz,
+    );
+    for name in &files {
+        if options.precomplete && name == PRECOMPLETE {
+            builder.add_compressed_entry(
+                PRECOMPLETE,
+                DEFAULT_FLAGS,
+                Cursor::new(precomplete(&files, &dirs)?.into_bytes()),
+                CompressionType::Xz,
+            );
+        } else {
+            let path = dir.join(name);
+            let compression = CompressionType::for_file(name, file_flags(&path));
+            builder.add_compressed_file(name.as_str(), path, compression);
+        }
+    }
+
+    builder.build(output)
+}
+
+/// Builds a partial update that takes an installation from the contents of the `old` complete
+/// update to the contents of the `new` one.
+///
+/// Changed files are included as `MBDIFF10` patches when the compressed patch is smaller than the
+/// compressed file, otherwise they are added in full. Files missing from `new` are removed, as is
+/// everything listed in its `removed-files`. The product information from `new` is copied to the
+/// output.
+pub fn build_partial_update<O, N, W>(old: &mut Mar<O>, new: &mut Mar<N>, output: W) -> Result<()>
+where
+    O: Read + Seek,
+    N: Read + Seek,
+    W: Write + Seek,
+{
+    let old_items = update_entries(old)?;
+    let new_items = update_entries(new)?;
+
+    let mut instructions = Vec::new();
+    let mut entries = Vec::new();
+
+    for item in &new_items {
+        let data = read_entry(new, item)?;
+
+        if is_add_if_not(&item.name) {
+            instructions.push(Instruction::AddIfNot {
+                test: item.name.clone(),
+                path: item.name.clone(),
+            });
+            entries.push((item.name.clone(), item.flags, compress_file(item, &data)?));
+            continue;
+        }
+
+        // Files whose mode changed are replaced since patching keeps the existing mode.
+        let old_item = old
+            .entry(&item.name)
+            .filter(|old_item| old_item.flags == item.flags)
+            .cloned();
+        let full = match old_item {
+            Some(old_item) => {
+                let old_data = read_entry(old, &old_item)?;
+                if old_data == data {
+                    continue;
+                }
+
+                let full = compress_file(item, &data)?;
+                let patch = compress(&create_patch(&old_data, &data)?, CompressionType::Xz)?;
+                if patch.len() < full.len() {
+                    let patch_name = format!("{}.patch", item.name);
+                    instructions.push(patch_instruction(&patch_name, &item.name));
+                    entries.push((patch_name, DEFAULT_FLAGS, patch));
+                    continue;
+                }
+                full
+            }
+            None => compress_file(item, &data)?,
+        };
+
+        instructions.push(add_instruction(&item.name));
+        entries.push((item.name.clone(), item.flags, full));
+    }
+
+    for item in &old_items {
+        if !new.contains(&item.name) {
+            instructions.push(Instruction::Remove {
+                path: item.name.clone(),
+            });
+        }
+    }
+
+    if let Some(item) = new.entry(REMOVED_FILES).cloned() {
+        let text = String::from_utf8_lossy(&read_entry(new, &item)?).into_owned();
+        for instruction in removed_files_instructions(&text) {
+            if !instructions.contains(&instruction) {
+                instructions.push(instruction);
+            }
+        }
+    }
+
+    let manifest = Manifest {
+        update_type: UpdateType::Partial,
+        instructions,
+    };
+    manifest.validate()?;
+
+    let mut builder = MarBuilder::new();
+    if let Some(info) = new.product_info()? {
+        builder.product_information(info);
+    }
+    builder.add_compressed_entry(
+        MANIFEST_NAME,
+        DEFAULT_FLAGS,
+        Cursor::new(manifest.to_string().into_bytes()),
+        CompressionType::Xz,
+    );
+    // The entries are already compressed.
+    for (name, flags, data) in entries {
+        builder.add_entry(name, flags, Cursor::new(data));
+    }
+
+    builder.build(output)
+}
+
+/// Lists the regular files and directories within `dir`, skipping any update manifests.
+fn list_dir(
+    dir: &Path,
+    prefix: &str,
+    files: &mut Vec<String>,
+    dirs: &mut Vec<String>,
+) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name().into_string().map_err(|name| {
+            MarError::InvalidInput(format!("{:?} is not a valid UTF-8 name", name))
+        })?;
+        let name = format!("{}{}", prefix, file_name);
+
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            let dir_name = format!("{}/", name);
+            list_dir(&entry.path(), &dir_name, files, dirs)?;
+            dirs.push(dir_name);
+        } else if file_type.is_file() && !MANIFEST_NAMES.contains(&file_name.as_str()) {
+            files.push(name);
+        }
+    }
+    Ok(())
+}
+
+/// Generates the `precomplete` file for the given files and directories.
+///
+/// Distribution files and files that are only added if missing are left out so they survive
+/// future complete updates.
+fn precomplete(files: &[String], dirs: &[String]) -> Result<String> {
+    let mut files: Vec<&String> = files
+        .iter()
+        .filter(|name| {
+            !name.contains("distribution/")
+                && !ADD_IF_NOT_FILES.iter().any(|file| name.ends_with(file))
+        })
+        .collect();
+    files.sort_by(|a, b| b.cmp(a));
+    let mut dirs: Vec<&String> = dirs
+        .iter()
+        .filter(|name| !name.contains("distribution/"))
+        .collect();
+    dirs.sort_by(|a, b| b.cmp(a));
+
+    let instructions = files
+        .into_iter()
+        .map(|path| Instruction::Remove { path: path.clone() })
+        .chain(
+            dirs.into_iter()
+                .map(|path| Instruction::RemoveDir { path: path.clone() }),
+        );
+
+    let mut text = String::new();
+    for instruction in instructions {
+        instruction.validate()?;
+        text.push_str(&instruction.to_string());
+        text.push('\n');
+    }
+    Ok(text)
+}
+
+/// Returns the entries of an update excluding its manifests.
+fn update_entries<R: Read + Seek>(archive: &mut Mar<R>) -> Result<Vec<MarItem>> {
+    let items = archive
+        .files()
+        .iter()
+        .filter(|item| !MANIFEST_NAMES.contains(&item.name.as_str()))
+        .cloned()
+        .collect();
+    Ok(items)
+}
+
+fn read_entry<R: Read + Seek>(archive: &mut Mar<R>, item: &MarItem) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    archive.read(item)?.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+fn compress(data: &[u8], compression: CompressionType) -> Result<Vec<u8>> {
+    let mut writer = CompressedWrite::new(Vec::new(), compression)?;
+    writer.write_all(data)?;
+    Ok(writer.finish()?)
+}
+
+fn compress_file(item: &MarItem, data: &[u8]) -> Result<Vec<u8>> {
+    compress(data, CompressionType::for_file(&item.name, item.flags))
+}
+
+fn is_add_if_not(name: &str) -> bool {
+    let file_name = name.rsplit('/').next().unwrap_or(name);
+    ADD_IF_NOT_FILES.contains(&file_name)
+}
+
+/// Returns the directory of the distribution extension a file belongs to, if any.
+fn extension_dir(name: &str) -> Option<&str> {
+    let rest = name.strip_prefix(EXTENSIONS_DIR)?;
+    let end = rest.find('/')?;
+    Some(&name[..EXTENSIONS_DIR.len() + end])
+}
+
+fn add_instruction(name: &str) -> Instruction {
+    match extension_dir(name) {
+        Some(test) => Instruction::AddIf {
+            test: test.to_owned(),
+            path: name.to_owned(),
+        },
+        None => Instruction::Add {
+            path: name.to_owned(),
+        },
+    }
+}
+
+fn patch_instruction(patch: &str, name: &str) -> Instruction {
+    match extension_dir(name) {
+        Some(test) => Instruction::PatchIf {
+            test: test.to_owned(),
+            patch: patch.to_owned(),
+            path: name.to_owned(),
+        },
+        None => Instruction::Patch {
+            patch: patch.to_owned(),
+            path: name.to_owned(),
+        },
+    }
+}
+
+/// Converts the lines of a `removed-files` list into instructions.
+///
+/// Lines ending in `/` remove an empty directory and lines ending in `/*` remove a directory and
+/// everything in it.
+fn removed_files_instructions(text: &str) -> Vec<Instruction> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            if let Some(dir) = line.strip_suffix('*').filter(|dir| dir.ends_with('/')) {
+                Instruction::RemoveDirRecursive { path: dir.into() }
+            } else if line.ends_with('/') {
+                Instruction::RemoveDir { path: line.into() }
+            } else {
+                Instruction::Remove { path: line.into() }
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::apply::apply;
+
+    /// Returns `len` bytes that don't compress, so patches are always smaller.
+    fn noise(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    fn write(dir: &Path, name: &str, data: &[u8], mode: u32) {
+        let path = dir.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, data).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        }
+        #[cfg(not(unix))]
+        let _ = mode;
+    }
+
+    /// Lists everything in a directory with the contents and mode of each file.
+    fn tree(dir: &Path) -> BTreeMap<String, Option<(Vec<u8>, u32)>> {
+        let mut files = Vec::new();
+        let mut dirs = Vec::new();
+        list_dir(dir, "", &mut files, &mut dirs).unwrap();
+        let files = files.into_iter().map(|name| {
+            let path = dir.join(&name);
+            let contents = fs::read(&path).unwrap();
+            (name, Some((contents, file_flags(&path))))
+        });
+        dirs.into_iter()
+            .map(|name| (name, None))
+            .chain(files)
+            .collect()
+    }
+
+    fn full_update(dir: &Path) -> Mar<Cursor<Vec<u8>>> {
+        let mut output = Cursor::new(Vec::new());
+        build_full_update(dir, &mut output, &FullUpdateOptions::new()).unwrap();
+        output.set_position(0);
+        Mar::from_buffer(output).unwrap()
+    }
+
+    #[test]
+    fn full_update_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let binary = noise(1024, 3);
+        write(dir, "firefox", &binary, 0o755);
+        write(dir, "libxul.so", &binary, 0o644);
+        write(dir, "readme.txt", b"readme", 0o644);
+        write(dir, "defaults/pref/channel-prefs.js", b"prefs", 0o644);
+        write(dir, "update-settings.ini", b"settings", 0o644);
+        write(dir, "distribution/extensions/ext/a.js", b"a", 0o644);
+        write(dir, "distribution/policies.json", b"{}", 0o644);
+        write(
+            dir,
+            "removed-files",
+            b"old.txt\nolddir/\n\n# comment\nolderdir/*\n",
+            0o644,
+        );
+
+        let mut update = full_update(dir);
+        let manifest = update.manifest().unwrap().unwrap();
+        assert_eq!(
+            manifest.to_string(),
+            "type \"complete\"\n\
+             add-if-not \"update-settings.ini\" \"update-settings.ini\"\n\
+             add \"removed-files\"\n\
+             add \"readme.txt\"\n\
+             add \"precomplete\"\n\
+             add \"libxul.so\"\n\
+             add \"firefox\"\n\
+             add \"distribution/policies.json\"\n\
+             add-if \"distribution/extensions/ext\" \"distribution/extensions/ext/a.js\"\n\
+             add-if-not \"defaults/pref/channel-prefs.js\" \"defaults/pref/channel-prefs.js\"\n\
+             remove \"old.txt\"\n\
+             rmdir \"olddir/\"\n\
+             rmrfdir \"olderdir/\"\n"
+        );
+
+        // Distribution files and files only added if missing are left out.
+        let item = update.entry(PRECOMPLETE).unwrap().clone();
+        assert_eq!(
+            String::from_utf8(read_entry(&mut update, &item).unwrap()).unwrap(),
+            "remove \"removed-files\"\n\
+             remove \"readme.txt\"\n\
+             remove \"precomplete\"\n\
+             remove \"libxul.so\"\n\
+             remove \"firefox\"\n\
+             rmdir \"defaults/pref/\"\n\
+             rmdir \"defaults/\"\n"
+        );
+
+        let raw = |update: &mut Mar<_>, name| {
+            let item = update.entry(name).unwrap().clone();
+            let mut data = Vec::new();
+            update
+                .read_raw(&item)
+                .unwrap()
+                .read_to_end(&mut data)
+                .unwrap();
+            data
+        };
+        let bcj = compress(&binary, CompressionType::XzBcj).unwrap();
+        assert_ne!(bcj, compress(&binary, CompressionType::Xz).unwrap());
+        assert_eq!(raw(&mut update, "libxul.so"), bcj);
+        #[cfg(unix)]
+        assert_eq!(raw(&mut update, "firefox"), bcj);
+        assert_eq!(
+            raw(&mut update, "readme.txt"),
+            compress(b"readme", CompressionType::Xz).unwrap()
+        );
+    }
+
+    #[test]
+    fn partial_update_round_trip() {
+        let root = tempfile::tempdir().unwrap();
+        let (old, new) = (root.path().join("old"), root.path().join("new"));
+        let big = noise(64 * 1024, 1);
+        let mut changed_big = big.clone();
+        changed_big[1000..1010].fill(0);
+        let extension = noise(32 * 1024, 2);
+        let mut changed_extension = extension.clone();
+        changed_extension[5000] ^= 1;
+
+        write(&old, "same.txt", b"same", 0o644);
+        write(&old, "big.bin", &big, 0o644);
+        write(&old, "tool", b"#!/bin/sh\n", 0o644);
+        write(&old, "gone.txt", b"gone", 0o644);
+        write(&old, "distribution/extensions/ext/x.js", &extension, 0o644);
+        write(&old, "removed-files", b"obsolete/\n", 0o644);
+
+        write(&new, "same.txt", b"same", 0o644);
+        write(&new, "big.bin", &changed_big, 0o644);
+        write(&new, "tool", b"#!/bin/sh\n", 0o755);
+        write(
+            &new,
+            "distribution/extensions/ext/x.js",
+            &changed_extension,
+            0o644,
+        );
+        write(&new, "distribution/extensions/ext/y.js", b"new", 0o644);
+        write(&new, "removed-files", b"obsolete/\n", 0o644);
+
+        let mut old_update = full_update(&old);
+        let mut new_update = full_update(&new);
+        let mut partial = Cursor::new(Vec::new());
+        build_partial_update(&mut old_update, &mut new_update, &mut partial).unwrap();
+        partial.set_position(0);
+        let mut partial = Mar::from_buffer(partial).unwrap();
+
+        let manifest = partial.manifest().unwrap().unwrap();
+        assert_eq!(manifest.update_type, UpdateType::Partial);
+        for instruction in [
+            Instruction::Patch {
+                patch: "big.bin.patch".to_owned(),
+                path: "big.bin".to_owned(),
+            },
+            Instruction::Add {
+                path: "tool".to_owned(),
+            },
+            Instruction::Remove {
+                path: "gone.txt".to_owned(),
+            },
+            Instruction::PatchIf {
+                test: "distribution/extensions/ext".to_owned(),
+                patch: "distribution/extensions/ext/x.js.patch".to_owned(),
+                path: "distribution/extensions/ext/x.js".to_owned(),
+            },
+            Instruction::AddIf {
+                test: "distribution/extensions/ext".to_owned(),
+                path: "distribution/extensions/ext/y.js".to_owned(),
+            },
+            Instruction::RemoveDir {
+                path: "obsolete/".to_owned(),
+            },
+        ] {
+            assert!(
+                manifest.instructions.contains(&instruction),
+                "{}",
+                instruction
+            );
+        }
+        // Unchanged files are left alone.
+        assert!(!manifest
+            .instructions
+            .iter()
+            .any(|instruction| instruction.to_string().contains("same.txt")));
+
+        let (updated, expected) = (root.path().join("updated"), root.path().join("expected"));
+        apply(&mut old_update, &updated).unwrap();
+        apply(&mut partial, &updated).unwrap();
+        apply(&mut new_update, &expected).unwrap();
+        assert_eq!(tree(&updated), tree(&expected));
+        #[cfg(unix)]
+        assert_eq!(tree(&updated)["tool"].as_ref().unwrap().1 & 0o777, 0o755);
+    }
+}
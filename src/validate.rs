@@ -0,0 +1,251 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Strict structural validation of MAR files, matching the checks in Firefox's `mar_read.c`.
+
+use std::io::{Read, Seek, SeekFrom};
+
+use byteorder::{BigEndian, ReadBytesExt};
+
+use crate::read::{read_next_item, ReadOptions, MAX_SIGNATURE_LENGTH, SIGNATURE_BLOCK_OFFSET};
+use crate::{MarError, MarFileInfo, Result};
+
+/// Largest MAR file Firefox will accept.
+const MAX_SIZE_OF_MAR_FILE: u64 = 500 * 1024 * 1024;
+
+/// Longest entry name accepted, matching `MAXPATHLEN` on Linux.
+const MAX_NAME_LENGTH: usize = 4096;
+
+/// Where the data of an entry lies.
+struct Span {
+    name: String,
+    start: u64,
+    end: u64,
+}
+
+/// Checks the structure of a MAR file, returning every problem found.
+///
+/// An empty list means the file is valid. Errors reading the file are returned directly.
+pub(crate) fn validate<R>(mut archive: R, info: &MarFileInfo) -> Result<Vec<MarError>>
+where
+    R: Read + Seek,
+{
+    let mut violations = Vec::new();
+
+    let file_size = archive.seek(SeekFrom::End(0))?;
+    if file_size > MAX_SIZE_OF_MAR_FILE {
+        violations.push(MarError::Malformed(format!(
+            "File is larger than {} bytes",
+            MAX_SIZE_OF_MAR_FILE
+        )));
+    }
+
+    let content_start = if info.has_signature_block {
+        blocks_end(&mut archive, info, file_size, &mut violations)?
+    } else {
+        // Old-style files have only the magic bytes and offset to the index before the content.
+        8
+    };
+
+    // The index was checked to lie within the file when it was opened.
+    archive.seek(SeekFrom::Start(info.offset_to_index as u64))?;
+    let size_of_index = archive.read_u32::<BigEndian>()?;
+    let mut index = vec![0; size_of_index as usize];
+    archive.read_exact(&mut index)?;
+
+    // Names that are not UTF-8 are read so they can be reported along with any other problems
+    // with their entries.
+    let mut options = ReadOptions::new();
+    options.non_utf8_names(true);
+
+    let mut spans = Vec::new();
+    let mut remaining = &index[..];
+    while !remaining.is_empty() {
+        let item = match read_next_item(&mut remaining, &options) {
+            Ok(item) => item,
+            Err(error) => {
+                // The start of the next entry is unknown.
+                violations.push(error);
+                break;
+            }
+        };
+        if !item.is_utf8_name() {
+            violations.push(MarError::InvalidUtf8Name {
+                bytes: item.name_bytes().to_vec(),
+            });
+        }
+        let name_length = item.name_bytes().len();
+        let offset = item.offset as u64;
+        let length = item.length as u64;
+        let name = item.name;
+
+        if name_length == 0 {
+            violations.push(MarError::InvalidName {
+                name: name.clone(),
+                reason: "is empty",
+            });
+        } else if name_length > MAX_NAME_LENGTH {
+            violations.push(MarError::InvalidName {
+                name: name.clone(),
+                reason: "is too long",
+            });
+        }
+
+        if offset < content_start {
+            violations.push(MarError::EntryOverlapsHeader { name: name.clone() });
+        }
+        if offset + length > info.offset_to_index as u64 {
+            violations.push(MarError::EntryOverlapsIndex { name: name.clone() });
+        }
+        if length > 0 {
+            spans.push(Span {
+                name,
+                start: offset,
+                end: offset + length,
+            });
+        }
+    }
+
+    // Compare each entry with whichever earlier entry extends furthest.
+    spans.sort_by_key(|span| span.start);
+    let mut furthest: Option<&Span> = None;
+    for span in &spans {
+        match furthest {
+            Some(previous) => {
+                if span.start < previous.end {
+                    violations.push(MarError::EntriesOverlap {
+                        first: previous.name.clone(),
+                        second: span.name.clone(),
+                    });
+                }
+                if span.end > previous.end {
+                    furthest = Some(span);
+                }
+            }
+            None => furthest = Some(span),
+        }
+    }
+
+    Ok(violations)
+}
+
+/// Checks the header, signature block and additional blocks, returning where they end.
+fn blocks_end<R>(
+    mut archive: R,
+    info: &MarFileInfo,
+    file_size: u64,
+    violations: &mut Vec<MarError>,
+) -> Result<u64>
+where
+    R: Read + Seek,
+{
+    archive.seek(SeekFrom::Start(8))?;
+    if archive.read_u64::<BigEndian>()? != file_size {
+        violations.push(MarError::Malformed(
+            "File size in the header does not match the file".to_owned(),
+        ));
+    }
+
+    let mut position = SIGNATURE_BLOCK_OFFSET + 4;
+    for _ in 0..info.num_signatures {
+        if position + 8 > file_size {
+            violations.push(MarError::SignatureOutOfBounds { offset: position });
+            return Ok(position);
+        }
+        archive.seek(SeekFrom::Start(position + 4))?;
+        let signature_len = archive.read_u32::<BigEndian>()?;
+        if signature_len > MAX_SIGNATURE_LENGTH {
+            violations.push(MarError::Malformed("Signature is too long".to_owned()));
+        }
+        position += 8 + signature_len as u64;
+    }
+
+    if info.has_additional_blocks {
+        position = info.offset_additional_blocks as u64;
+        for _ in 0..info.num_additional_blocks {
+            if position + 8 > file_size {
+                violations.push(MarError::AdditionalBlockOutOfBounds { offset: position });
+                return Ok(position);
+            }
+            archive.seek(SeekFrom::Start(position))?;
+            let size = archive.read_u32::<BigEndian>()?;
+            if size < 8 {
+                violations.push(MarError::Malformed(
+                    "Additional block is too small".to_owned(),
+                ));
+                return Ok(position);
+            }
+            position += size as u64;
+        }
+    }
+
+    if position > info.offset_to_index as u64 {
+        violations.push(MarError::Malformed(
+            "Signature and additional blocks extend into the index".to_owned(),
+        ));
+    }
+
+    Ok(position)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::slice::MarSlice;
+    use crate::testing::{self, build};
+    use crate::{Mar, MarError};
+
+    fn archive() -> Vec<u8> {
+        let mut builder = testing::builder();
+        builder.add_entry("a12.txt", 0o644, &b"hello"[..]);
+        build(builder)
+    }
+
+    #[test]
+    fn valid_archive() {
+        let mut mar = Mar::from_buffer(Cursor::new(archive())).unwrap();
+        assert!(mar.validate().unwrap().is_empty());
+    }
+
+    #[test]
+    fn additional_block_out_of_bounds() {
+        let mut data = archive();
+        let info = crate::read::get_info(Cursor::new(&data)).unwrap();
+        let count = info.offset_additional_blocks as usize - 4;
+        data[count..count + 4].copy_from_slice(&3_u32.to_be_bytes());
+
+        let mut mar = Mar::from_buffer(Cursor::new(data)).unwrap();
+        let violations = mar.validate().unwrap();
+        assert!(violations
+            .iter()
+            .any(|v| matches!(v, MarError::AdditionalBlockOutOfBounds { .. })));
+    }
+
+    #[test]
+    fn invalid_names() {
+        let mut data = archive();
+        let position = data.windows(7).position(|w| w == b"a12.txt").unwrap();
+        data[position + 1] = 0xff;
+
+        let violations = MarSlice::new(&data).unwrap().validate().unwrap();
+        assert!(matches!(
+            violations.as_slice(),
+            [MarError::InvalidUtf8Name { bytes }] if bytes == b"a\xff2.txt"
+        ));
+    }
+
+    #[test]
+    fn malformed_index() {
+        let mut data = archive();
+        let len = data.len();
+        data[len - 1] = b'x';
+
+        let violations = MarSlice::new(&data).unwrap().validate().unwrap();
+        assert!(matches!(
+            violations.as_slice(),
+            [MarError::Malformed(message)] if message == "Index ends with an unterminated name"
+        ));
+    }
+}
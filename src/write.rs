@@ -0,0 +1,512 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Utilities for creating MAR files.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use byteorder::{BigEndian, WriteBytesExt};
+
+use crate::compression::{CompressedWrite, CompressionType};
+use crate::read::{find_additional_block, get_info, MAR_ID};
+use crate::{signing, AdditionalBlock, MarError, ProductInformation, Result};
+
+/// Identifier of the product information additional block.
+pub(crate) const PRODUCT_INFO_BLOCK_ID: u32 = 1;
+
+/// Maximum length of the MAR channel ID, excluding its NUL terminator.
+pub(crate) const MAX_MAR_CHANNEL_ID_SIZE: usize = 63;
+
+/// Maximum length of the product version, excluding its NUL terminator.
+pub(crate) const MAX_PRODUCT_VERSION_SIZE: usize = 31;
+
+/// Size of the product information block. Firefox always reserves space for the maximum length
+/// strings so that the block can be rewritten in place.
+pub(crate) const PRODUCT_INFO_BLOCK_SIZE: u32 =
+    (4 + 4 + MAX_MAR_CHANNEL_ID_SIZE + 1 + MAX_PRODUCT_VERSION_SIZE + 1) as u32;
+
+/// Default file mode for entries whose permissions are unknown.
+pub(crate) const DEFAULT_FLAGS: u32 = 0o644;
+
+/// Returns the mode to store for a local file, taken from its permissions where the platform
+/// supports it.
+pub(crate) fn file_flags(path: &Path) -> u32 {
+    #[cfg(unix)]
+    let flags = fs::metadata(path)
+        .map(|m| m.permissions().mode() & 0o777)
+        .unwrap_or(DEFAULT_FLAGS);
+    #[cfg(not(unix))]
+    let flags = {
+        let _ = path;
+        DEFAULT_FLAGS
+    };
+    flags
+}
+
+/// Where the data for an entry comes from.
+enum Source<'a> {
+    Reader(Box<dyn Read + 'a>),
+    Path(PathBuf),
+}
+
+struct Entry<'a> {
+    name: String,
+    flags: u32,
+    compression: CompressionType,
+    source: Source<'a>,
+}
+
+/// Creates a new MAR file from a list of entries.
+///
+/// Entry data is written to the archive exactly as given unless it is added with one of the
+/// `add_compressed_*` methods.
+pub struct MarBuilder<'a> {
+    blocks: Vec<AdditionalBlock>,
+    entries: Vec<Entry<'a>>,
+}
+
+impl<'a> Default for MarBuilder<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> MarBuilder<'a> {
+    /// Creates an empty builder.
+    pub fn new() -> Self {
+        Self {
+            blocks: Vec::new(),
+            entries: Vec::new(),
+        }
+    }
+
+    /// Sets the product information to include in the archive.
+    pub fn product_information(&mut self, info: ProductInformation) -> &mut Self {
+        let block = AdditionalBlock::ProductInformation(info);
+        match self
+            .blocks
+            .iter_mut()
+            .find(|b| matches!(b, AdditionalBlock::ProductInformation(_)))
+        {
+            Some(existing) => *existing = block,
+            None => self.blocks.insert(0, block),
+        }
+        self
+    }
+
+    /// Adds an additional block to include in the archive.
+    pub fn additional_block(&mut self, block: AdditionalBlock) -> &mut Self {
+        self.blocks.push(block);
+        self
+    }
+
+    /// Adds an entry whose content will be read from the given reader.
+    pub fn add_entry<R>(&mut self, name: impl Into<String>, flags: u32, data: R) -> &mut Self
+    where
+        R: Read + 'a,
+    {
+        self.add_compressed_entry(name, flags, data, CompressionType::None)
+    }
+
+    /// Adds an entry whose content will be read from the given reader and compressed.
+    pub fn add_compressed_entry<R>(
+        &mut self,
+        name: impl Into<String>,
+        flags: u32,
+        data: R,
+        compression: CompressionType,
+    ) -> &mut Self
+    where
+        R: Read + 'a,
+    {
+        self.entries.push(Entry {
+            name: name.into(),
+            flags,
+            compression,
+            source: Source::Reader(Box::new(data)),
+        });
+        self
+    }
+
+    /// Adds an entry whose content will be read from a local file.
+    ///
+    /// The file is not opened until the archive is built. The file mode is taken from the file's
+    /// permissions where the platform supports it.
+    pub fn add_file<P: AsRef<Path>>(&mut self, name: impl Into<String>, path: P) -> &mut Self {
+        self.add_compressed_file(name, path, CompressionType::None)
+    }
+
+    /// Adds an entry whose content will be read from a local file and compressed.
+    ///
+    /// Behaves as `add_file` otherwise.
+    pub fn add_compressed_file<P: AsRef<Path>>(
+        &mut self,
+        name: impl Into<String>,
+        path: P,
+        compression: CompressionType,
+    ) -> &mut Self {
+        let path = path.as_ref().to_owned();
+
+        self.entries.push(Entry {
+            name: name.into(),
+            flags: file_flags(&path),
+            compression,
+            source: Source::Path(path),
+        });
+        self
+    }
+
+    /// Writes the archive to the given output.
+    pub fn build<W>(self, mut output: W) -> Result<()>
+    where
+        W: Write + Seek,
+    {
+        for entry in &self.entries {
+            validate_name(&entry.name)?;
+        }
+        for block in &self.blocks {
+            write_additional_block(io::sink(), block)?;
+        }
+
+        let start = output.stream_position()?;
+
+        // Write the header, the offset to the index and file size are filled in at the end.
+        output.write_all(MAR_ID)?;
+        output.write_u32::<BigEndian>(0)?;
+        output.write_u64::<BigEndian>(0)?;
+
+        // Write an empty signature block.
+        output.write_u32::<BigEndian>(0)?;
+
+        // Write the additional blocks.
+        output.write_u32::<BigEndian>(self.blocks.len() as u32)?;
+        for block in &self.blocks {
+            write_additional_block(&mut output, block)?;
+        }
+
+        // Write the content of each entry, building up the index as we go.
+        let mut index = Vec::new();
+        for entry in self.entries {
+            let offset = to_u32(output.stream_position()? - start)?;
+            let mut writer = CompressedWrite::new(&mut output, entry.compression)?;
+            match entry.source {
+                Source::Reader(mut reader) => io::copy(&mut reader, &mut writer)?,
+                Source::Path(path) => io::copy(&mut File::open(path)?, &mut writer)?,
+            };
+            writer.finish()?;
+            let length = to_u32(output.stream_position()? - start - offset as u64)?;
+            to_u32(offset as u64 + length as u64)?;
+
+            index.write_u32::<BigEndian>(offset)?;
+            index.write_u32::<BigEndian>(length)?;
+            index.write_u32::<BigEndian>(entry.flags)?;
+            index.write_all(entry.name.as_bytes())?;
+            index.write_u8(0)?;
+        }
+
+        // Write the index.
+        let offset_to_index = to_u32(output.stream_position()? - start)?;
+        output.write_u32::<BigEndian>(to_u32(index.len() as u64)?)?;
+        output.write_all(&index)?;
+
+        // Fill in the header.
+        let end = output.stream_position()?;
+        output.seek(SeekFrom::Start(start + MAR_ID.len() as u64))?;
+        output.write_u32::<BigEndian>(offset_to_index)?;
+        output.write_u64::<BigEndian>(end - start)?;
+        output.seek(SeekFrom::Start(end))?;
+        Ok(output.flush()?)
+    }
+}
+
+/// Replaces the product information in an existing MAR file.
+///
+/// The new information is written in place so the archive must already contain a product
+/// information block large enough to hold it. Signed archives are rejected since changing them
+/// would invalidate their signatures.
+pub fn set_product_info<F>(mut archive: F, info: &ProductInformation) -> Result<()>
+where
+    F: Read + Write + Seek,
+{
+    let mar_info = get_info(&mut archive)?;
+    if mar_info.num_signatures > 0 {
+        return Err(MarError::InvalidInput(
+            "Cannot change the product information of a signed MAR file".to_owned(),
+        ));
+    }
+
+    let (position, size) =
+        match find_additional_block(&mut archive, &mar_info, PRODUCT_INFO_BLOCK_ID)? {
+            Some(block) => block,
+            None => {
+                return Err(MarError::InvalidInput(
+                    "MAR file has no product information block".to_owned(),
+                ))
+            }
+        };
+
+    let mut block = Vec::new();
+    write_product_info_block(&mut block, info)?;
+    let used = 8 + info.mar_channel_id.len() + 1 + info.product_version.len() + 1;
+    if used > size as usize {
+        return Err(MarError::InvalidInput(
+            "Product information does not fit in the existing block".to_owned(),
+        ));
+    }
+
+    // Keep the existing block size, zero filling whatever is left.
+    block.resize(size as usize, 0);
+    (&mut block[0..4]).write_u32::<BigEndian>(size)?;
+
+    archive.seek(SeekFrom::Start(position))?;
+    archive.write_all(&block)?;
+    Ok(archive.flush()?)
+}
+
+/// Copies a MAR file to `output`, replacing all of its additional blocks.
+///
+/// Blocks are written in the order given, so unknown blocks read from an archive can be passed
+/// back unchanged. Signed archives are rejected since changing them would invalidate their
+/// signatures.
+pub fn set_additional_blocks<R, W>(
+    mut input: R,
+    output: W,
+    blocks: &[AdditionalBlock],
+) -> Result<()>
+where
+    R: Read + Seek,
+    W: Write + Seek,
+{
+    if get_info(&mut input)?.num_signatures > 0 {
+        return Err(MarError::InvalidInput(
+            "Cannot change the additional blocks of a signed MAR file".to_owned(),
+        ));
+    }
+
+    signing::repackage(input, output, &[], Some(blocks), io::sink())?;
+    Ok(())
+}
+
+/// Writes an additional block, including its size and identifier.
+pub(crate) fn write_additional_block<W: Write>(
+    mut output: W,
+    block: &AdditionalBlock,
+) -> Result<()> {
+    match block {
+        AdditionalBlock::ProductInformation(info) => write_product_info_block(output, info),
+        AdditionalBlock::Unknown { id, .. } if *id == PRODUCT_INFO_BLOCK_ID => {
+            Err(MarError::InvalidInput(
+                "Product information must be given as AdditionalBlock::ProductInformation"
+                    .to_owned(),
+            ))
+        }
+        AdditionalBlock::Unknown { id, data } => {
+            let size = to_u32(8 + data.len() as u64)?;
+            output.write_u32::<BigEndian>(size)?;
+            output.write_u32::<BigEndian>(*id)?;
+            Ok(output.write_all(data)?)
+        }
+    }
+}
+
+/// Writes a product information block, including its size and identifier.
+pub(crate) fn write_product_info_block<W: Write>(
+    mut output: W,
+    info: &ProductInformation,
+) -> Result<()> {
+    let channel_id = info.mar_channel_id.as_bytes();
+    let version = info.product_version.as_bytes();
+    if channel_id.len() > MAX_MAR_CHANNEL_ID_SIZE || channel_id.contains(&0) {
+        return Err(MarError::InvalidInput(
+            "MAR channel ID must be at most 63 bytes and not contain NUL".to_owned(),
+        ));
+    }
+    if version.len() > MAX_PRODUCT_VERSION_SIZE || version.contains(&0) {
+        return Err(MarError::InvalidInput(
+            "Product version must be at most 31 bytes and not contain NUL".to_owned(),
+        ));
+    }
+
+    let mut block = Vec::with_capacity(PRODUCT_INFO_BLOCK_SIZE as usize);
+    block.write_u32::<BigEndian>(PRODUCT_INFO_BLOCK_SIZE)?;
+    block.write_u32::<BigEndian>(PRODUCT_INFO_BLOCK_ID)?;
+    block.write_all(channel_id)?;
+    block.write_u8(0)?;
+    block.write_all(version)?;
+    block.write_u8(0)?;
+    block.resize(PRODUCT_INFO_BLOCK_SIZE as usize, 0);
+
+    Ok(output.write_all(&block)?)
+}
+
+/// Checks that a name can be stored in the index.
+fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty() {
+        return Err(MarError::InvalidInput(
+            "File name must not be empty".to_owned(),
+        ));
+    }
+    if name.contains('\0') {
+        return Err(MarError::InvalidInput(format!(
+            "File name {:?} must not contain NUL",
+            name
+        )));
+    }
+    Ok(())
+}
+
+fn to_u32(value: u64) -> Result<u32> {
+    u32::try_from(value).map_err(|_| MarError::InvalidInput("MAR file size overflow".to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::read::get_info;
+    use crate::testing::{self, build, build_with_signature_slot, product_info, read_entry};
+
+    #[test]
+    fn build_round_trip() {
+        let mut builder = testing::builder();
+        builder.add_entry("a.txt", 0o644, &b"hello"[..]);
+        builder.add_entry("dir/empty", 0o600, &b""[..]);
+        builder.add_compressed_entry(
+            "dir/bin",
+            0o755,
+            &b"compressed content"[..],
+            CompressionType::XzBcj,
+        );
+        let mut archive = Cursor::new(Vec::new());
+        builder.build(&mut archive).unwrap();
+        let size = archive.get_ref().len() as u64;
+        assert_eq!(archive.get_ref()[8..16], size.to_be_bytes());
+
+        let mut mar = crate::Mar::from_buffer_strict(archive).unwrap();
+        assert_eq!(mar.product_info().unwrap(), Some(product_info()));
+        assert_eq!(mar.len(), 3);
+
+        let names = mar.sorted_entries().map(|item| item.name.clone());
+        assert_eq!(names.collect::<Vec<_>>(), ["a.txt", "dir/bin", "dir/empty"]);
+        let item = mar.entry("a.txt").unwrap();
+        assert_eq!((item.length, item.flags), (5, 0o644));
+        let item = mar.entry("dir/empty").unwrap();
+        assert_eq!((item.length, item.flags), (0, 0o600));
+        assert_eq!(mar.entry("dir/bin").unwrap().flags, 0o755);
+
+        assert_eq!(read_entry(&mut mar, "a.txt"), b"hello");
+        assert_eq!(read_entry(&mut mar, "dir/empty"), b"");
+        assert_eq!(read_entry(&mut mar, "dir/bin"), b"compressed content");
+        assert!(mar.validate().unwrap().is_empty());
+    }
+
+    #[test]
+    fn build_rejects_unknown_product_info_block() {
+        let mut builder = MarBuilder::new();
+        builder.additional_block(AdditionalBlock::Unknown {
+            id: PRODUCT_INFO_BLOCK_ID,
+            data: vec![0; 8],
+        });
+        let result = builder.build(Cursor::new(Vec::new()));
+        assert!(matches!(result, Err(MarError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn set_product_info_in_place() {
+        let mut builder = testing::builder();
+        builder.add_entry("a.txt", 0o644, &b"hello"[..]);
+        let original = build(builder);
+        let block_start = get_info(Cursor::new(&original))
+            .unwrap()
+            .offset_additional_blocks as usize;
+
+        let info = ProductInformation {
+            mar_channel_id: "nightly-test".to_owned(),
+            product_version: "101.0a1".to_owned(),
+        };
+        let mut archive = Cursor::new(original.clone());
+        set_product_info(&mut archive, &info).unwrap();
+
+        let archive = archive.into_inner();
+        assert_eq!(archive.len(), original.len());
+        assert_eq!(
+            archive[block_start..block_start + 4],
+            PRODUCT_INFO_BLOCK_SIZE.to_be_bytes()
+        );
+        let mut mar = crate::Mar::from_buffer_strict(Cursor::new(archive)).unwrap();
+        assert_eq!(mar.product_info().unwrap(), Some(info));
+        assert_eq!(read_entry(&mut mar, "a.txt"), b"hello");
+        assert!(mar.validate().unwrap().is_empty());
+    }
+
+    #[test]
+    fn set_product_info_rejects_unsuitable_archives() {
+        let mut builder = testing::builder();
+        builder.add_entry("a.txt", 0o644, &b"hello"[..]);
+        let mut signed = Cursor::new(build_with_signature_slot(builder));
+        let result = set_product_info(&mut signed, &product_info());
+        assert!(matches!(result, Err(MarError::InvalidInput(_))));
+
+        let mut builder = MarBuilder::new();
+        builder.add_entry("a.txt", 0o644, &b"hello"[..]);
+        let mut without_block = Cursor::new(build(builder));
+        let result = set_product_info(&mut without_block, &product_info());
+        assert!(matches!(result, Err(MarError::InvalidInput(_))));
+
+        let mut archive = Cursor::new(build(testing::builder()));
+        let too_long = ProductInformation {
+            mar_channel_id: "x".repeat(PRODUCT_INFO_BLOCK_SIZE as usize),
+            product_version: "1.0".to_owned(),
+        };
+        let result = set_product_info(&mut archive, &too_long);
+        assert!(matches!(result, Err(MarError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn set_additional_blocks_keeps_original_bytes() {
+        let mut builder = testing::builder();
+        builder.add_entry("a.txt", 0o644, &b"hello"[..]);
+
+        // Put something in the padding of the product information block.
+        let mut bytes = build(builder);
+        let info = get_info(Cursor::new(&bytes)).unwrap();
+        let block_start = info.offset_additional_blocks as usize;
+        let block = block_start..block_start + PRODUCT_INFO_BLOCK_SIZE as usize;
+        bytes[block.end - 1] = 0xaa;
+
+        let extra = AdditionalBlock::Unknown {
+            id: 5,
+            data: b"extra".to_vec(),
+        };
+        let mut output = Cursor::new(Vec::new());
+        set_additional_blocks(
+            Cursor::new(&bytes),
+            &mut output,
+            &[
+                AdditionalBlock::ProductInformation(product_info()),
+                extra.clone(),
+            ],
+        )
+        .unwrap();
+
+        let output = output.into_inner();
+        assert_eq!(output[block.clone()], bytes[block]);
+        let mut mar = crate::Mar::from_buffer(Cursor::new(output)).unwrap();
+        let blocks = mar
+            .additional_blocks()
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            blocks,
+            [AdditionalBlock::ProductInformation(product_info()), extra]
+        );
+        assert_eq!(read_entry(&mut mar, "a.txt"), b"hello");
+    }
+}